@@ -1,42 +1,100 @@
-use std::fmt;
+use std::{fmt, rc::Rc};
 
-use crate::scanner::{Number, Token, TokenType};
+use super::{printer, stmt::FunctionDecl};
+use crate::{
+    runtime::string::Str,
+    scanner::{Number, Token},
+    source::SourceId,
+};
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+/// Identifies an expression which refers to a variable, so that where the
+/// variable lives can be recorded for it. Ids are assigned by the parser and
+/// are unique across every source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId {
+    pub source: SourceId,
+    pub index: u32,
+}
+
+#[derive(Clone)]
 pub enum Expr {
     Assign {
+        id: ExprId,
         name: Token,
         value: Box<Expr>,
     },
     Binary {
         left: Box<Expr>,
-        operator: TokenType,
+        operator: Token,
         right: Box<Expr>,
     },
     Call {
         callee: Box<Expr>,
+        paren: Token,
         arguments: Vec<Expr>,
     },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
     Grouping(Box<Expr>),
+    Index {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+    },
+    IndexSet {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    /// A string with expressions embedded in it, made by joining the values of
+    /// its parts
+    Interpolation(Vec<Expr>),
+    Lambda(Rc<FunctionDecl>),
+    List(Vec<Expr>),
+    Map {
+        brace: Token,
+        entries: Vec<(Expr, Expr)>,
+    },
     Literal(Literal),
     Logical {
         left: Box<Expr>,
-        operator: TokenType,
+        operator: Token,
         right: Box<Expr>,
     },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+    },
+    This {
+        id: ExprId,
+        keyword: Token,
+    },
     Unary {
-        operator: TokenType,
+        operator: Token,
         right: Box<Expr>,
     },
     Variable {
+        id: ExprId,
         name: Token,
     },
 }
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+// Shown as an S-expression, which is far easier to read than the derived form
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&printer::expr(self))
+    }
+}
+
+#[derive(Clone)]
 pub enum Literal {
+    Integer(i64),
     Number(Number),
-    String(String),
+    String(Str),
     True,
     False,
     Nil,
@@ -45,6 +103,7 @@ pub enum Literal {
 impl fmt::Debug for Literal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Integer(num) => num.fmt(f),
             Self::Number(num) => num.fmt(f),
             Self::String(str) => str.fmt(f),
             Self::True => write!(f, "true"),
@@ -0,0 +1,187 @@
+//! Renders the AST as Lisp-style S-expressions, in the style of the book's
+//! AstPrinter. Syntactic sugar has already been removed by the parser, so a
+//! `for` loop shows up as the block and `while` it was desugared to.
+
+use super::{
+    expr::{Expr, Literal},
+    stmt::{FunctionDecl, Stmt},
+};
+use crate::runtime::value::Value;
+
+/// Renders every statement on a line of its own
+pub fn print(statements: &[Stmt]) -> String {
+    statements
+        .iter()
+        .map(|statement| stmt(statement) + "\n")
+        .collect()
+}
+
+pub fn stmt(statement: &Stmt) -> String {
+    match statement {
+        Stmt::Block(statements) => parenthesize("block", statements.iter().map(stmt)),
+        Stmt::Break { .. } => "(break)".to_string(),
+        Stmt::ClassDecl { name, methods } => parenthesize(
+            &format!("class {}", name.lexeme),
+            methods.iter().map(|method| function(method)),
+        ),
+        Stmt::Continue { .. } => "(continue)".to_string(),
+        Stmt::Expression(expression) => parenthesize(";", [expr(expression)]),
+        Stmt::FunctionDecl(declaration) => function(declaration),
+        Stmt::Import { path, name, .. } => match name {
+            Some(name) => format!("(import {} {:?})", name.lexeme, path),
+            None => format!("(import {:?})", path),
+        },
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            let mut parts = vec![expr(condition), stmt(then_branch)];
+            parts.extend(else_branch.as_deref().map(stmt));
+            parenthesize("if", parts)
+        }
+        Stmt::Print(expression) => parenthesize("print", [expr(expression)]),
+        Stmt::Return { value, .. } => parenthesize("return", value.iter().map(expr)),
+        Stmt::Throw { value, .. } => parenthesize("throw", [expr(value)]),
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => {
+            let mut parts = vec![parenthesize("block", body.iter().map(stmt))];
+            if let Some(catch) = catch {
+                let head = format!("catch {}", catch.name.lexeme);
+                parts.push(parenthesize(&head, catch.body.iter().map(stmt)));
+            }
+            if let Some(finally) = finally {
+                parts.push(parenthesize("finally", finally.iter().map(stmt)));
+            }
+            parenthesize("try", parts)
+        }
+        // The increment of a desugared `for` loop comes last, as it runs after
+        // the body
+        Stmt::While {
+            condition,
+            body,
+            increment,
+        } => {
+            let mut parts = vec![expr(condition), stmt(body)];
+            parts.extend(increment.iter().map(expr));
+            parenthesize("while", parts)
+        }
+        Stmt::VarDecl { name, initializer } => parenthesize(
+            &format!("var {}", name.lexeme),
+            initializer.iter().map(expr),
+        ),
+    }
+}
+
+pub fn expr(expression: &Expr) -> String {
+    match expression {
+        Expr::Assign { name, value, .. } => {
+            parenthesize(&format!("= {}", name.lexeme), [expr(value)])
+        }
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        }
+        | Expr::Logical {
+            left,
+            operator,
+            right,
+        } => parenthesize(&operator.lexeme, [expr(left), expr(right)]),
+        Expr::Call {
+            callee, arguments, ..
+        } => parenthesize(
+            "call",
+            [expr(callee)].into_iter().chain(arguments.iter().map(expr)),
+        ),
+        Expr::Get { object, name } => parenthesize(".", [expr(object), name.lexeme.clone()]),
+        Expr::Grouping(expression) => parenthesize("group", [expr(expression)]),
+        Expr::Index { object, index, .. } => parenthesize("index", [expr(object), expr(index)]),
+        Expr::IndexSet {
+            object,
+            index,
+            value,
+            ..
+        } => parenthesize("index=", [expr(object), expr(index), expr(value)]),
+        Expr::Interpolation(parts) => parenthesize("str", parts.iter().map(expr)),
+        Expr::Lambda(declaration) => function(declaration),
+        Expr::List(elements) => parenthesize("list", elements.iter().map(expr)),
+        Expr::Map { entries, .. } => parenthesize(
+            "map",
+            entries
+                .iter()
+                .map(|(key, value)| format!("({} {})", expr(key), expr(value))),
+        ),
+        Expr::Literal(literal) => match literal {
+            Literal::Integer(n) => n.to_string(),
+            Literal::Number(n) => Value::Number(n.0).to_string(),
+            Literal::String(s) => format!("{:?}", s),
+            Literal::True => "true".to_string(),
+            Literal::False => "false".to_string(),
+            Literal::Nil => "nil".to_string(),
+        },
+        Expr::Set {
+            object,
+            name,
+            value,
+        } => parenthesize(".=", [expr(object), name.lexeme.clone(), expr(value)]),
+        Expr::This { .. } => "this".to_string(),
+        Expr::Unary { operator, right } => parenthesize(&operator.lexeme, [expr(right)]),
+        Expr::Variable { name, .. } => name.lexeme.clone(),
+    }
+}
+
+/// Renders a declared function, method or lambda, which has no name
+fn function(declaration: &FunctionDecl) -> String {
+    let params: Vec<&str> = declaration
+        .params
+        .iter()
+        .map(|param| param.lexeme.as_str())
+        .collect();
+    let head = match &declaration.name {
+        Some(name) => format!("fun {} ({})", name.lexeme, params.join(" ")),
+        None => format!("fun ({})", params.join(" ")),
+    };
+    parenthesize(&head, declaration.body.iter().map(stmt))
+}
+
+fn parenthesize(head: &str, parts: impl IntoIterator<Item = String>) -> String {
+    let mut out = format!("({}", head);
+    for part in parts {
+        out.push(' ');
+        out.push_str(&part);
+    }
+    out.push(')');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::print;
+    use crate::{parser::Parser, scanner::scan_tokens, source::SourceId};
+
+    fn dump(source: &str) -> String {
+        let tokens = scan_tokens(source, SourceId::default()).unwrap();
+        print(&Parser::new(tokens).parse().unwrap())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            dump("print -1 + 2 * (3 - a) == !b or c;"),
+            "(print (or (== (+ (- 1) (* 2 (group (- 3 a)))) (! b)) c))\n"
+        );
+    }
+
+    #[test]
+    fn desugared_for() {
+        assert_eq!(
+            dump("for (var i = 0; i < 3; i = i + 1) print i;"),
+            "(block (var i 0) (while (< i 3) (print i) (= i (+ i 1))))\n"
+        );
+        assert_eq!(dump("for (;;) break;"), "(while true (break))\n");
+    }
+}
@@ -1,11 +1,30 @@
-use super::expr::Expr;
+use std::{fmt, rc::Rc};
+
+use super::{expr::Expr, printer};
 use crate::scanner::Token;
 
 #[derive(Clone)]
 pub enum Stmt {
     Block(Vec<Stmt>),
+    Break {
+        keyword: Token,
+    },
+    ClassDecl {
+        name: Token,
+        methods: Vec<Rc<FunctionDecl>>,
+    },
+    Continue {
+        keyword: Token,
+    },
     Expression(Expr),
-    FunctionDecl(FunctionDecl),
+    FunctionDecl(Rc<FunctionDecl>),
+    Import {
+        keyword: Token,
+        path: String,
+        // Name to bind the module to. When None, all of the module's exports are
+        // bound instead.
+        name: Option<Token>,
+    },
     If {
         condition: Expr,
         then_branch: Box<Stmt>,
@@ -16,9 +35,21 @@ pub enum Stmt {
         keyword: Token,
         value: Option<Expr>,
     },
+    Throw {
+        keyword: Token,
+        value: Expr,
+    },
+    Try {
+        body: Vec<Stmt>,
+        catch: Option<CatchClause>,
+        finally: Option<Vec<Stmt>>,
+    },
     While {
         condition: Expr,
         body: Box<Stmt>,
+        // Only set for desugared `for` loops. Kept separate from the body so that
+        // `continue` still runs it.
+        increment: Option<Expr>,
     },
     VarDecl {
         name: Token,
@@ -26,9 +57,32 @@ pub enum Stmt {
     },
 }
 
+impl fmt::Debug for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&printer::stmt(self))
+    }
+}
+
 #[derive(Clone)]
 pub struct FunctionDecl {
-    pub name: Token,
+    // None for anonymous functions
+    pub name: Option<Token>,
     pub params: Vec<Token>,
     pub body: Vec<Stmt>,
 }
+
+impl FunctionDecl {
+    /// Name of a function declared with a statement or a method, which are always
+    /// named
+    pub fn declared_name(&self) -> &Token {
+        self.name
+            .as_ref()
+            .expect("Declared functions always have a name")
+    }
+}
+
+#[derive(Clone)]
+pub struct CatchClause {
+    pub name: Token,
+    pub body: Vec<Stmt>,
+}
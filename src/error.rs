@@ -1,23 +1,335 @@
-use anyhow::anyhow;
-use log::error;
+use std::fmt;
 
-use crate::scanner::{Token, TokenType};
+use crate::{
+    runtime::{function::CallFrame, value::Value},
+    scanner::{Token, TokenType},
+    source::SourceId,
+};
 
-pub fn make_error(token: &Token, message: &str) -> anyhow::Error {
-    match token.token_type {
-        TokenType::Eof => (anyhow!("{} at end", message)),
-        _ => (anyhow!("{} at '{}'", message, token.lexeme)),
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Location of some source code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub source: SourceId,
+    /// 1-based line of the start of the span
+    pub line: usize,
+    /// 1-based column of the start of the span
+    pub col: u32,
+    /// Byte offset of the start of the span
+    pub start: usize,
+    /// Byte offset one past the end of the span
+    pub end: usize,
+}
+
+impl From<&Token> for Span {
+    fn from(token: &Token) -> Self {
+        token.span
+    }
+}
+
+/// Identifies the kind of a failure, so it can be inspected without parsing
+/// the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    // Scan errors
+    UnexpectedCharacter,
+    UnterminatedString,
+    InvalidEscape,
+    UnterminatedInterpolation,
+    InvalidNumber,
+
+    // Parse errors
+    ExpectedToken,
+    ExpectedExpression,
+    InvalidAssignmentTarget,
+    TooManyParameters,
+    TooManyArguments,
+    EmptyInterpolation,
+
+    // Resolve errors
+    AlreadyDeclared,
+    ReadInOwnInitializer,
+    ReturnFromTopLevel,
+    ReturnFromInitializer,
+    ThisOutsideClass,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    ImportOutsideTopLevel,
+
+    // Compile errors
+    TooManyConstants,
+    TooManyLocals,
+    TooManyUpvalues,
+    TooManyElements,
+    JumpTooLarge,
+
+    // Runtime errors
+    TypeMismatch,
+    UndefinedVariable,
+    UndefinedProperty,
+    NotCallable,
+    ArityMismatch,
+    NotIndexable,
+    InvalidIndex,
+    IndexOutOfRange,
+    InvalidMapKey,
+    UndefinedKey,
+    EmptyList,
+    ImportFailed,
+    ImportCycle,
+    UncaughtException,
+    StackOverflow,
+    IntegerOverflow,
+    DivisionByZero,
+}
+
+impl ErrorCode {
+    /// Stable identifier, grouped by phase: E1xx scan, E2xx parse, E3xx resolve,
+    /// E4xx runtime and E5xx bytecode compilation
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::UnexpectedCharacter => "E101",
+            ErrorCode::UnterminatedString => "E102",
+            ErrorCode::InvalidEscape => "E103",
+            ErrorCode::UnterminatedInterpolation => "E104",
+            ErrorCode::InvalidNumber => "E105",
+            ErrorCode::ExpectedToken => "E201",
+            ErrorCode::ExpectedExpression => "E202",
+            ErrorCode::InvalidAssignmentTarget => "E203",
+            ErrorCode::TooManyParameters => "E204",
+            ErrorCode::TooManyArguments => "E205",
+            ErrorCode::EmptyInterpolation => "E206",
+            ErrorCode::AlreadyDeclared => "E301",
+            ErrorCode::ReadInOwnInitializer => "E302",
+            ErrorCode::ReturnFromTopLevel => "E303",
+            ErrorCode::ReturnFromInitializer => "E304",
+            ErrorCode::ThisOutsideClass => "E305",
+            ErrorCode::BreakOutsideLoop => "E306",
+            ErrorCode::ContinueOutsideLoop => "E307",
+            ErrorCode::ImportOutsideTopLevel => "E308",
+            ErrorCode::TypeMismatch => "E401",
+            ErrorCode::UndefinedVariable => "E402",
+            ErrorCode::UndefinedProperty => "E403",
+            ErrorCode::NotCallable => "E404",
+            ErrorCode::ArityMismatch => "E405",
+            ErrorCode::NotIndexable => "E406",
+            ErrorCode::InvalidIndex => "E407",
+            ErrorCode::IndexOutOfRange => "E408",
+            ErrorCode::InvalidMapKey => "E409",
+            ErrorCode::UndefinedKey => "E410",
+            ErrorCode::EmptyList => "E411",
+            ErrorCode::ImportFailed => "E412",
+            ErrorCode::ImportCycle => "E413",
+            ErrorCode::UncaughtException => "E414",
+            ErrorCode::StackOverflow => "E415",
+            ErrorCode::IntegerOverflow => "E416",
+            ErrorCode::DivisionByZero => "E417",
+            ErrorCode::TooManyConstants => "E501",
+            ErrorCode::TooManyLocals => "E502",
+            ErrorCode::TooManyUpvalues => "E503",
+            ErrorCode::TooManyElements => "E504",
+            ErrorCode::JumpTooLarge => "E505",
+        }
     }
 }
 
-pub fn report_error(token: &Token, message: &str) {
-    match token.token_type {
-        TokenType::Eof => (error!("{} at end", message)),
-        _ => {
-            (error!(
-                "[line {}, col {}] {} at '{}'",
-                token.line, token.col, message, token.lexeme
-            ))
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Span,
+    /// Extra context about why the error happened
+    pub notes: Vec<String>,
+    /// A suggestion of how to fix the error
+    pub help: Option<String>,
+    /// Calls which were active when a runtime error happened, innermost first
+    pub backtrace: Vec<CallFrame>,
+}
+
+impl Diagnostic {
+    pub fn new(code: ErrorCode, message: impl Into<String>, span: Span) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            span,
+            notes: vec![],
+            help: None,
+            backtrace: vec![],
         }
     }
 }
+
+// Payloads are boxed to keep results small on the happy path
+#[derive(Debug, Clone)]
+pub enum Error {
+    Scan(Box<Diagnostic>),
+    Parse(Box<Diagnostic>),
+    Resolve(Box<Diagnostic>),
+    /// A limit of the bytecode format was exceeded
+    Compile(Box<Diagnostic>),
+    Runtime(Box<Diagnostic>),
+    /// A value raised by `throw`, which only becomes a failure if it is never
+    /// caught
+    Thrown {
+        value: Box<Value>,
+        diagnostic: Box<Diagnostic>,
+    },
+}
+
+impl Error {
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            Error::Scan(diagnostic)
+            | Error::Parse(diagnostic)
+            | Error::Resolve(diagnostic)
+            | Error::Compile(diagnostic)
+            | Error::Runtime(diagnostic)
+            | Error::Thrown { diagnostic, .. } => diagnostic,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.diagnostic().code
+    }
+
+    pub fn span(&self) -> Span {
+        self.diagnostic().span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.diagnostic().message
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.diagnostic_mut().notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.diagnostic_mut().help = Some(help.into());
+        self
+    }
+
+    pub fn diagnostic_mut(&mut self) -> &mut Diagnostic {
+        match self {
+            Error::Scan(diagnostic)
+            | Error::Parse(diagnostic)
+            | Error::Resolve(diagnostic)
+            | Error::Compile(diagnostic)
+            | Error::Runtime(diagnostic)
+            | Error::Thrown { diagnostic, .. } => diagnostic,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Diagnostic {
+            code,
+            message,
+            span,
+            ..
+        } = self.diagnostic();
+        write!(
+            f,
+            "[line {}, col {}] error[{}]: {}",
+            span.line, span.col, code, message
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Builds a parse or resolve error pointing at `token`
+fn static_diagnostic(token: &Token, code: ErrorCode, message: &str) -> Diagnostic {
+    let message = match token.token_type {
+        TokenType::Eof => format!("{} at end", message),
+        _ => format!("{} at '{}'", message, token.lexeme),
+    };
+    Diagnostic::new(code, message, token.span)
+}
+
+pub fn parse_error(token: &Token, code: ErrorCode, message: &str) -> Error {
+    Error::Parse(Box::new(static_diagnostic(token, code, message)))
+}
+
+pub fn resolve_error(token: &Token, code: ErrorCode, message: &str) -> Error {
+    Error::Resolve(Box::new(static_diagnostic(token, code, message)))
+}
+
+pub fn compile_error(at: Span, code: ErrorCode, message: &str) -> Error {
+    Error::Compile(Box::new(Diagnostic::new(code, message, at)))
+}
+
+pub fn runtime_error(at: impl Into<Span>, code: ErrorCode, message: impl Into<String>) -> Error {
+    Error::Runtime(Box::new(Diagnostic::new(code, message, at.into())))
+}
+
+/// The error raised by `throw`ing `value`
+pub fn thrown_error(at: impl Into<Span>, value: Value) -> Error {
+    let diagnostic = Diagnostic::new(
+        ErrorCode::UncaughtException,
+        format!("Uncaught exception: {}", value),
+        at.into(),
+    );
+    Error::Thrown {
+        value: Box::new(value),
+        diagnostic: Box::new(diagnostic),
+    }
+    .with_help("thrown values can be caught with try/catch")
+}
+
+/// The error raised by a call which would nest deeper than `max_depth` calls
+pub fn stack_overflow_error(at: impl Into<Span>, max_depth: usize) -> Error {
+    runtime_error(at, ErrorCode::StackOverflow, "Stack overflow").with_help(format!(
+        "calls can only be nested {} deep, which can be changed with --max-depth",
+        max_depth
+    ))
+}
+
+/// A runtime failure raised somewhere without access to the source, such as an
+/// environment or native function. It is located with ErrorLocation::at once
+/// it reaches the interpreter.
+#[derive(Debug)]
+pub struct Fault {
+    pub code: ErrorCode,
+    pub message: String,
+    pub help: Option<String>,
+}
+
+impl Fault {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+pub trait ErrorLocation<T> {
+    /// Turns a fault into a runtime error at a token or span
+    fn at(self, at: impl Into<Span>) -> Result<T>;
+}
+
+impl<T> ErrorLocation<T> for Result<T, Fault> {
+    fn at(self, at: impl Into<Span>) -> Result<T> {
+        self.map_err(|fault| {
+            let mut diagnostic = Diagnostic::new(fault.code, fault.message, at.into());
+            diagnostic.help = fault.help;
+            Error::Runtime(Box::new(diagnostic))
+        })
+    }
+}
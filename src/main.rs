@@ -1,70 +1,291 @@
 use std::{
     env, fs,
-    io::{self, Write},
-    process,
+    io::{self, IsTerminal, Write},
+    path::Path,
+    process, thread,
 };
 
-use anyhow::Result;
-use log::error;
-use runtime::interpreter::Interpreter;
+use std::rc::Rc;
 
-use crate::resolver::Resolver;
+use runtime::{
+    function::DEFAULT_MAX_DEPTH,
+    interpreter::{self, Interpreter},
+    loader::Loader,
+};
+use vm::{compiler, machine::Vm, object::CompiledFunction};
+
+use crate::{
+    ast::{printer, stmt::Stmt},
+    error::{Error, Result},
+    resolver::{Locals, Resolver},
+    source::SourceMap,
+};
 
 mod ast;
 mod error;
+mod optimizer;
 mod parser;
+mod report;
 mod resolver;
 mod runtime;
 mod scanner;
+mod source;
+mod vm;
+
+// Exit codes from sysexits.h
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_OSERR: i32 = 71;
+const EX_IOERR: i32 = 74;
+
+/// Why running some source failed
+enum Failure {
+    /// The source was rejected before any of it ran
+    Static(Vec<Error>),
+    Runtime(Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Static(_) => EX_DATAERR,
+            Failure::Runtime(_) => EX_SOFTWARE,
+        }
+    }
+}
+
+/// Something which can run resolved programs
+trait Backend {
+    /// A program ready to run
+    type Program;
+
+    fn loader(&self) -> &Loader;
+    fn loader_mut(&mut self) -> &mut Loader;
+    fn set_max_depth(&mut self, max_depth: usize);
+    fn prepare(
+        &mut self,
+        statements: Vec<Stmt>,
+        locals: Locals,
+    ) -> Result<Self::Program, Vec<Error>>;
+    fn execute(&mut self, program: Self::Program) -> Result<()>;
+}
+
+impl Backend for Interpreter {
+    type Program = Vec<Stmt>;
+
+    fn loader(&self) -> &Loader {
+        self.loader()
+    }
+
+    fn loader_mut(&mut self) -> &mut Loader {
+        self.loader_mut()
+    }
+
+    fn set_max_depth(&mut self, max_depth: usize) {
+        self.set_max_depth(max_depth)
+    }
+
+    fn prepare(&mut self, statements: Vec<Stmt>, locals: Locals) -> Result<Vec<Stmt>, Vec<Error>> {
+        self.resolve(locals);
+        Ok(statements)
+    }
+
+    fn execute(&mut self, statements: Vec<Stmt>) -> Result<()> {
+        self.interpret(&statements)
+    }
+}
+
+impl Backend for Vm {
+    type Program = Rc<CompiledFunction>;
+
+    fn loader(&self) -> &Loader {
+        self.loader()
+    }
+
+    fn loader_mut(&mut self) -> &mut Loader {
+        self.loader_mut()
+    }
+
+    fn set_max_depth(&mut self, max_depth: usize) {
+        self.set_max_depth(max_depth)
+    }
+
+    fn prepare(
+        &mut self,
+        statements: Vec<Stmt>,
+        locals: Locals,
+    ) -> Result<Rc<CompiledFunction>, Vec<Error>> {
+        compiler::compile(&statements, &locals).map_err(|e| vec![e])
+    }
+
+    fn execute(&mut self, script: Rc<CompiledFunction>) -> Result<()> {
+        self.interpret(script)
+    }
+}
 
 fn main() {
-    pretty_env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
-        println!("Usage: jlox [script]");
-        process::exit(64);
-    } else if let Some(arg) = args.get(1) {
-        run_file(arg);
-    } else {
-        run_prompt();
+    // The tree-walker is the default backend
+    let mut use_vm = false;
+    let mut dump = false;
+    let mut max_depth = DEFAULT_MAX_DEPTH;
+    let mut script = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--vm" => use_vm = true,
+            "--dump-ast" => dump = true,
+            "--max-depth" => {
+                max_depth = match args.next().map(|depth| depth.parse()) {
+                    Some(Ok(depth)) => depth,
+                    _ => usage(),
+                }
+            }
+            _ if script.is_none() && !arg.starts_with("--") => script = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    match (script, use_vm) {
+        (Some(path), _) if dump => dump_ast(&path),
+        (None, _) if dump => usage(),
+        (script, false) => start_interpreter(script, max_depth),
+        (script, true) => start(Vm::new(), script, max_depth),
+    }
+}
+
+/// Runs the tree-walker, which recurses on the native stack for every Lox call
+/// so needs far more room than the main thread has to reach the maximum depth
+fn start_interpreter(script: Option<String>, max_depth: usize) {
+    if max_depth > interpreter::MAX_DEPTH {
+        eprintln!(
+            "error: --max-depth can be at most {} without --vm",
+            interpreter::MAX_DEPTH
+        );
+        process::exit(EX_USAGE);
+    }
+    let main = thread::Builder::new()
+        .stack_size(Interpreter::stack_size(max_depth))
+        .spawn(move || start(Interpreter::new(), script, max_depth))
+        .unwrap_or_else(|e| {
+            eprintln!("error: can't start the interpreter: {}", e);
+            process::exit(EX_OSERR);
+        });
+    if main.join().is_err() {
+        process::exit(EX_SOFTWARE);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: jlox [--vm] [--dump-ast] [--max-depth <calls>] [script]");
+    process::exit(EX_USAGE);
+}
+
+/// Runs the file at `path`, or the prompt if there isn't one
+fn start(mut backend: impl Backend, path: Option<String>, max_depth: usize) {
+    backend.set_max_depth(max_depth);
+    match path {
+        Some(path) => run_file(backend, &path),
+        None => run_prompt(backend),
+    }
+}
+
+/// Prints the statements parsed from the file at `path` instead of running
+/// them
+fn dump_ast(path: &str) {
+    let contents = fs::read_to_string(path).unwrap_or_else(|e| read_failed(path, e));
+    let mut sources = SourceMap::default();
+    let id = sources.add(path, contents.as_str());
+    let statements = scanner::scan_tokens(&contents, id)
+        .map_err(|e| vec![e])
+        .and_then(|tokens| parser::Parser::new(tokens).parse());
+    match statements {
+        Ok(statements) => print!("{}", printer::print(&statements)),
+        Err(errors) => {
+            report(errors, &sources);
+            process::exit(EX_DATAERR);
+        }
     }
 }
 
-fn run_file(path: &str) {
-    let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
-    let mut interpreter = Interpreter::new();
-    run_errored(&mut interpreter, &contents);
+fn read_failed(path: &str, e: io::Error) -> ! {
+    eprintln!("error: can't read '{}': {}", path, e);
+    process::exit(EX_IOERR);
 }
 
-fn run_prompt() {
+fn run_file(mut backend: impl Backend, path: &str) {
+    let contents = fs::read_to_string(path)
+        .and_then(|contents| {
+            backend
+                .loader_mut()
+                .set_script_path(Path::new(path))
+                .map(|_| contents)
+        })
+        .unwrap_or_else(|e| read_failed(path, e));
+    if let Err(code) = run_errored(&mut backend, path, &contents) {
+        process::exit(code);
+    }
+}
+
+fn run_prompt(mut backend: impl Backend) {
     let stdin = io::stdin();
-    let mut interpreter = Interpreter::new();
     loop {
         print!("> ");
         io::stdout().flush().expect("flush failed!");
         let mut buf = String::new();
-        stdin
-            .read_line(&mut buf)
-            .expect("Something went wrong reading from stdin");
-        run_errored(&mut interpreter, buf.trim());
+        match stdin.read_line(&mut buf) {
+            // End of input
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("error: can't read from stdin: {}", e);
+                process::exit(EX_IOERR);
+            }
+        }
+        // Errors are reported but don't end the session
+        let _ = run_errored(&mut backend, "<prompt>", buf.trim());
     }
 }
 
-fn run_errored(interpreter: &mut Interpreter, source: &str) {
-    match run(interpreter, source) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("{}", e);
-        }
+/// Runs `source`, reporting any errors to stderr. Fails with the exit code the
+/// errors warrant.
+fn run_errored(backend: &mut impl Backend, name: &str, source: &str) -> Result<(), i32> {
+    let failure = match run(backend, name, source) {
+        Ok(_) => return Ok(()),
+        Err(failure) => failure,
+    };
+
+    let code = failure.exit_code();
+    let errors = match failure {
+        Failure::Static(errors) => errors,
+        Failure::Runtime(error) => vec![error],
+    };
+    report(errors, backend.loader().sources());
+    Err(code)
+}
+
+/// Renders errors to stderr
+fn report(errors: Vec<Error>, sources: &SourceMap) {
+    // Only colour diagnostics for a person to read
+    let colour = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    for e in errors {
+        eprint!("{}", report::render(&e, sources, colour));
     }
 }
 
-fn run(interpreter: &mut Interpreter, source: &str) -> Result<()> {
-    let tokens = scanner::scan_tokens(source)?;
+fn run(backend: &mut impl Backend, name: &str, source: &str) -> Result<(), Failure> {
+    let id = backend.loader_mut().add_source(name, source);
+    let tokens = scanner::scan_tokens(source, id).map_err(|e| Failure::Static(vec![e]))?;
     let parser = parser::Parser::new(tokens);
-    let statements = parser.parse()?;
-    let mut resolver = Resolver::new(interpreter);
-    resolver.resolve_statements(&statements);
-    interpreter.interpret(&statements)?;
-    Ok(())
+    let statements = parser.parse().map_err(Failure::Static)?;
+    // Nothing runs if the resolver finds any errors, even in code the optimizer
+    // would remove
+    let locals = Resolver::new()
+        .resolve(&statements)
+        .map_err(Failure::Static)?;
+    let statements = optimizer::optimize(statements);
+    let program = backend
+        .prepare(statements, locals)
+        .map_err(Failure::Static)?;
+    backend.execute(program).map_err(Failure::Runtime)
 }
@@ -0,0 +1,305 @@
+//! Simplifies code once it has been resolved. Operators whose operands are all
+//! literals are folded into the literal they evaluate to, and branches and
+//! loops whose conditions are literals are removed if they can never run.
+//!
+//! Operators which would fail at runtime, such as `"a" - 1`, are left alone so
+//! that they still fail when and where they would have done. Code is resolved
+//! before it's removed, so static errors within it are still reported, and
+//! removing whole blocks never changes the slots of the variables left.
+
+use std::rc::Rc;
+
+use crate::{
+    ast::{
+        expr::{Expr, Literal},
+        stmt::{CatchClause, FunctionDecl, Stmt},
+    },
+    runtime::{ops, value::Value},
+    scanner::{Number, TokenType},
+};
+
+pub fn optimize(statements: Vec<Stmt>) -> Vec<Stmt> {
+    statements.into_iter().filter_map(statement).collect()
+}
+
+/// Optimises a statement, or removes it if it would never do anything
+fn statement(stmt: Stmt) -> Option<Stmt> {
+    let optimized = match stmt {
+        Stmt::Block(statements) => Stmt::Block(optimize(statements)),
+        Stmt::ClassDecl { name, methods } => Stmt::ClassDecl {
+            name,
+            methods: methods.into_iter().map(function).collect(),
+        },
+        Stmt::Expression(expression) => Stmt::Expression(expr(expression)),
+        Stmt::FunctionDecl(declaration) => Stmt::FunctionDecl(function(declaration)),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            let condition = expr(condition);
+            match literal(&condition) {
+                Some(value) if value.is_truthy() => return statement(*then_branch),
+                Some(_) => return else_branch.and_then(|branch| statement(*branch)),
+                None => Stmt::If {
+                    condition,
+                    then_branch: Box::new(nested(*then_branch)),
+                    else_branch: else_branch
+                        .and_then(|branch| statement(*branch))
+                        .map(Box::new),
+                },
+            }
+        }
+        Stmt::Print(expression) => Stmt::Print(expr(expression)),
+        Stmt::Return { keyword, value } => Stmt::Return {
+            keyword,
+            value: value.map(expr),
+        },
+        Stmt::Throw { keyword, value } => Stmt::Throw {
+            keyword,
+            value: expr(value),
+        },
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => Stmt::Try {
+            body: optimize(body),
+            catch: catch.map(|catch| CatchClause {
+                name: catch.name,
+                body: optimize(catch.body),
+            }),
+            finally: finally.map(optimize),
+        },
+        Stmt::While {
+            condition,
+            body,
+            increment,
+        } => {
+            let condition = expr(condition);
+            if literal(&condition).is_some_and(|value| !value.is_truthy()) {
+                return None;
+            }
+            Stmt::While {
+                condition,
+                body: Box::new(nested(*body)),
+                increment: increment.map(expr),
+            }
+        }
+        Stmt::VarDecl { name, initializer } => Stmt::VarDecl {
+            name,
+            initializer: initializer.map(expr),
+        },
+        Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Import { .. } => stmt,
+    };
+    Some(optimized)
+}
+
+/// Optimises a statement which has to be kept, such as the body of a loop
+fn nested(body: Stmt) -> Stmt {
+    statement(body).unwrap_or(Stmt::Block(vec![]))
+}
+
+fn function(declaration: Rc<FunctionDecl>) -> Rc<FunctionDecl> {
+    let declaration = Rc::unwrap_or_clone(declaration);
+    Rc::new(FunctionDecl {
+        body: optimize(declaration.body),
+        ..declaration
+    })
+}
+
+fn expr(expression: Expr) -> Expr {
+    match expression {
+        Expr::Assign { id, name, value } => Expr::Assign {
+            id,
+            name,
+            value: Box::new(expr(*value)),
+        },
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        } => {
+            let left = expr(*left);
+            let right = expr(*right);
+            let folded = match (literal(&left), literal(&right)) {
+                (Some(l), Some(r)) => ops::binary(&operator.token_type, l, r).ok(),
+                _ => None,
+            };
+            folded.and_then(to_literal).unwrap_or_else(|| Expr::Binary {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            })
+        }
+        Expr::Call {
+            callee,
+            paren,
+            arguments,
+        } => Expr::Call {
+            callee: Box::new(expr(*callee)),
+            paren,
+            arguments: arguments.into_iter().map(expr).collect(),
+        },
+        Expr::Get { object, name } => Expr::Get {
+            object: Box::new(expr(*object)),
+            name,
+        },
+        Expr::Grouping(inner) => {
+            let inner = expr(*inner);
+            match inner {
+                Expr::Literal(_) => inner,
+                _ => Expr::Grouping(Box::new(inner)),
+            }
+        }
+        Expr::Index {
+            object,
+            bracket,
+            index,
+        } => Expr::Index {
+            object: Box::new(expr(*object)),
+            bracket,
+            index: Box::new(expr(*index)),
+        },
+        Expr::IndexSet {
+            object,
+            bracket,
+            index,
+            value,
+        } => Expr::IndexSet {
+            object: Box::new(expr(*object)),
+            bracket,
+            index: Box::new(expr(*index)),
+            value: Box::new(expr(*value)),
+        },
+        Expr::Interpolation(parts) => {
+            let parts: Vec<Expr> = parts.into_iter().map(expr).collect();
+            match parts.iter().map(literal).collect::<Option<Vec<Value>>>() {
+                Some(values) => {
+                    let string: String = values.iter().map(Value::to_string).collect();
+                    Expr::Literal(Literal::String(string.into()))
+                }
+                None => Expr::Interpolation(parts),
+            }
+        }
+        Expr::Lambda(declaration) => Expr::Lambda(function(declaration)),
+        Expr::List(elements) => Expr::List(elements.into_iter().map(expr).collect()),
+        Expr::Map { brace, entries } => Expr::Map {
+            brace,
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| (expr(key), expr(value)))
+                .collect(),
+        },
+        Expr::Logical {
+            left,
+            operator,
+            right,
+        } => {
+            let left = expr(*left);
+            let right = expr(*right);
+            // The right operand is only evaluated if the left doesn't decide the
+            // result
+            match literal(&left).map(|value| value.is_truthy()) {
+                Some(true) if operator.token_type == TokenType::Or => left,
+                Some(false) if operator.token_type == TokenType::And => left,
+                Some(_) => right,
+                None => Expr::Logical {
+                    left: Box::new(left),
+                    operator,
+                    right: Box::new(right),
+                },
+            }
+        }
+        Expr::Set {
+            object,
+            name,
+            value,
+        } => Expr::Set {
+            object: Box::new(expr(*object)),
+            name,
+            value: Box::new(expr(*value)),
+        },
+        Expr::Unary { operator, right } => {
+            let right = expr(*right);
+            let folded = literal(&right).and_then(|value| match operator.token_type {
+                TokenType::Minus => ops::negate(value).ok(),
+                TokenType::Bang => Some(Value::Boolean(!value.is_truthy())),
+                _ => unreachable!(),
+            });
+            folded.and_then(to_literal).unwrap_or_else(|| Expr::Unary {
+                operator,
+                right: Box::new(right),
+            })
+        }
+        Expr::Literal(_) | Expr::This { .. } | Expr::Variable { .. } => expression,
+    }
+}
+
+/// The value of an expression which is a literal
+fn literal(expression: &Expr) -> Option<Value> {
+    let Expr::Literal(literal) = expression else {
+        return None;
+    };
+    Some(match literal {
+        Literal::Integer(n) => Value::Integer(*n),
+        Literal::Number(n) => Value::Number(n.0),
+        Literal::String(s) => Value::String(s.clone()),
+        Literal::True => Value::Boolean(true),
+        Literal::False => Value::Boolean(false),
+        Literal::Nil => Value::Nil,
+    })
+}
+
+/// The literal which evaluates to `value`, if there is one
+fn to_literal(value: Value) -> Option<Expr> {
+    let literal = match value {
+        Value::Integer(n) => Literal::Integer(n),
+        Value::Number(n) => Literal::Number(Number(n)),
+        Value::String(s) => Literal::String(s),
+        Value::Boolean(true) => Literal::True,
+        Value::Boolean(false) => Literal::False,
+        Value::Nil => Literal::Nil,
+        _ => return None,
+    };
+    Some(Expr::Literal(literal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::{ast::printer::print, parser::Parser, scanner::scan_tokens, source::SourceId};
+
+    fn optimized(source: &str) -> String {
+        let tokens = scan_tokens(source, SourceId::default()).unwrap();
+        print(&optimize(Parser::new(tokens).parse().unwrap()))
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimized("print 1 + 2 * 3;"), "(print 7)\n");
+        assert_eq!(optimized("print -(1 + 1) < 0 and a;"), "(print a)\n");
+        assert_eq!(
+            optimized("print \"a\" + \"b\" == \"ab\";"),
+            "(print true)\n"
+        );
+        // Left for the runtime to report
+        assert_eq!(optimized("print \"a\" - 1;"), "(print (- \"a\" 1))\n");
+    }
+
+    #[test]
+    fn removes_dead_branches() {
+        assert_eq!(
+            optimized("if (false) print 1; else print 2; if (nil) print 3; while (!true) print 4;"),
+            "(print 2)\n"
+        );
+        assert_eq!(
+            optimized("for (var i = 0; false; i = i + 1) print i;"),
+            "(block (var i 0))\n"
+        );
+        assert_eq!(
+            optimized("while (a) if (0 > 1) print 1;"),
+            "(while a (block))\n"
+        );
+    }
+}
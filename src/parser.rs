@@ -1,20 +1,122 @@
-use std::cell::Cell;
-
-use anyhow::{anyhow, Result};
-use log::error;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
 use crate::{
     ast::{
-        expr::{Expr, Literal},
-        stmt::{FunctionDecl, Stmt},
+        expr::{Expr, ExprId, Literal},
+        stmt::{CatchClause, FunctionDecl, Stmt},
     },
-    error::make_error,
-    scanner::{Token, TokenType},
+    error::{parse_error, Error, ErrorCode, Result},
+    runtime::string::Str,
+    scanner::{self, Token, TokenType},
 };
 
+/// Binding power of an operator, from loosest to tightest.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type PrefixFn = fn(&Parser, &Token) -> Result<Expr>;
+type InfixFn = fn(&Parser, Expr, &Token) -> Result<Expr>;
+
+struct ParseRule {
+    /// Parses an expression starting with this token
+    prefix: Option<PrefixFn>,
+    /// Parses an expression where this token follows a left operand
+    infix: Option<InfixFn>,
+    /// Binding power of the token when used as an infix operator
+    precedence: Precedence,
+}
+
+/// The operator table. Adding an operator only requires adding its token here.
+fn get_rule(token_type: &TokenType) -> ParseRule {
+    let (prefix, infix, precedence): (Option<PrefixFn>, Option<InfixFn>, Precedence) =
+        match token_type {
+            TokenType::LeftParen => (Some(Parser::grouping), Some(Parser::call), Precedence::Call),
+            TokenType::LeftBrace => (Some(Parser::map), None, Precedence::None),
+            TokenType::LeftBracket => (Some(Parser::list), Some(Parser::index), Precedence::Call),
+            TokenType::Dot => (None, Some(Parser::dot), Precedence::Call),
+            TokenType::Minus => (Some(Parser::unary), Some(Parser::binary), Precedence::Term),
+            TokenType::Plus => (None, Some(Parser::binary), Precedence::Term),
+            TokenType::Percent | TokenType::Slash | TokenType::Star => {
+                (None, Some(Parser::binary), Precedence::Factor)
+            }
+            TokenType::Bang => (Some(Parser::unary), None, Precedence::None),
+            TokenType::BangEqual | TokenType::EqualEqual => {
+                (None, Some(Parser::binary), Precedence::Equality)
+            }
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => (None, Some(Parser::binary), Precedence::Comparison),
+            TokenType::Equal => (None, Some(Parser::assignment), Precedence::Assignment),
+            TokenType::And => (None, Some(Parser::logical), Precedence::And),
+            TokenType::Or => (None, Some(Parser::logical), Precedence::Or),
+            TokenType::Identifier => (Some(Parser::variable), None, Precedence::None),
+            TokenType::This => (Some(Parser::this), None, Precedence::None),
+            TokenType::Fun => (Some(Parser::lambda), None, Precedence::None),
+            TokenType::Interpolation(_) => (Some(Parser::interpolation), None, Precedence::None),
+            TokenType::String(_)
+            | TokenType::Integer(_)
+            | TokenType::Number(_)
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::True => (Some(Parser::literal), None, Precedence::None),
+            _ => (None, None, Precedence::None),
+        };
+    ParseRule {
+        prefix,
+        infix,
+        precedence,
+    }
+}
+
+/// Whether `token` is the rest of an interpolated string, following the `}`
+/// which closes an expression embedded in it
+fn is_continuation(token: &Token) -> bool {
+    matches!(
+        token.token_type,
+        TokenType::String(_) | TokenType::Interpolation(_)
+    ) && token.lexeme.starts_with('}')
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
     current: Cell<usize>,
+    // Index of the next expression id to assign
+    next_id: Cell<u32>,
+    // Errors from statements which have been skipped over
+    errors: RefCell<Vec<Error>>,
 }
 
 impl Parser {
@@ -22,21 +124,27 @@ impl Parser {
         Parser {
             tokens,
             current: Cell::new(0),
+            next_id: Cell::new(0),
+            errors: RefCell::new(vec![]),
         }
     }
 
-    pub fn parse(&self) -> Result<Vec<Stmt>> {
-        if self.tokens.is_empty() {
-            return Err(anyhow!("No tokens provided"));
-        }
-
+    /// Parses tokens ending with Eof, as produced by the scanner. Parsing
+    /// recovers after each error, so every error in the program is returned.
+    pub fn parse(&self) -> Result<Vec<Stmt>, Vec<Error>> {
         let mut statements = vec![];
         while !self.is_at_end() {
             if let Some(statement) = self.declaration() {
                 statements.push(statement);
             }
         }
-        Ok(statements)
+
+        let errors = self.errors.take();
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
     }
 
     fn advance(&self) -> &Token {
@@ -47,6 +155,17 @@ impl Parser {
         prev
     }
 
+    /// An id for a new expression in the same source as `token`. Every source
+    /// is parsed once, so ids never repeat.
+    fn next_id(&self, token: &Token) -> ExprId {
+        let index = self.next_id.get();
+        self.next_id.set(index + 1);
+        ExprId {
+            source: token.span.source,
+            index,
+        }
+    }
+
     fn consume_matching(&self, token_types: &[TokenType]) -> Option<&Token> {
         for token_type in token_types {
             if self.check(token_type) {
@@ -60,13 +179,20 @@ impl Parser {
         if self.check(token_type) {
             return Ok(self.advance());
         }
-        Err(make_error(self.peek(), message))
+        Err(parse_error(self.peek(), ErrorCode::ExpectedToken, message))
     }
 
     fn check(&self, token_type: &TokenType) -> bool {
         &self.peek().token_type == token_type
     }
 
+    fn check_next(&self, token_type: &TokenType) -> bool {
+        match self.tokens.get(self.current.get() + 1) {
+            Some(token) => &token.token_type == token_type,
+            None => false,
+        }
+    }
+
     fn is_at_end(&self) -> bool {
         matches!(self.peek().token_type, TokenType::Eof)
     }
@@ -86,13 +212,16 @@ impl Parser {
 
             match self.peek().token_type {
                 TokenType::Class
+                | TokenType::Import
                 | TokenType::Fun
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return,
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try => return,
                 _ => {}
             }
 
@@ -105,20 +234,30 @@ impl Parser {
         // Similar to using consume_matching(), but using match. Need to make sure we
         // call advance manually though.
         let result = match self.peek().token_type {
+            TokenType::Class => {
+                self.advance();
+                self.class_declaration()
+            }
             TokenType::Var => {
                 self.advance();
                 self.variable_declaration()
             }
-            TokenType::Fun => {
+            // Only a named `fun` is a declaration, otherwise it starts an anonymous
+            // function in an expression statement
+            TokenType::Fun if self.check_next(&TokenType::Identifier) => {
                 self.advance();
-                self.function_declaration("function")
+                self.function("function").map(Stmt::FunctionDecl)
+            }
+            TokenType::Import => {
+                let keyword = self.advance().clone();
+                self.import_declaration(keyword)
             }
             _ => self.statement(),
         };
         match result {
             Ok(s) => Some(s),
             Err(e) => {
-                error!("{}", e);
+                self.errors.borrow_mut().push(e);
                 self.synchronize();
                 None
             }
@@ -144,7 +283,59 @@ impl Parser {
         })
     }
 
-    fn function_declaration(&self, kind: &str) -> Result<Stmt> {
+    fn import_declaration(&self, keyword: Token) -> Result<Stmt> {
+        // `from` is only special here, so it isn't a keyword
+        let name = if self.check(&TokenType::Identifier) {
+            let name = self.advance().clone();
+            let from = self.consume(&TokenType::Identifier, "Expect 'from' after module name")?;
+            if from.lexeme != "from" {
+                return Err(parse_error(
+                    from,
+                    ErrorCode::ExpectedToken,
+                    "Expect 'from' after module name",
+                ));
+            }
+            Some(name)
+        } else {
+            None
+        };
+
+        let path = self.advance();
+        let path = match &path.token_type {
+            TokenType::String(path) => path.clone(),
+            _ => {
+                return Err(parse_error(
+                    path,
+                    ErrorCode::ExpectedToken,
+                    "Expect module path string",
+                ))
+            }
+        };
+        self.consume(&TokenType::Semicolon, "Expect ';' after import")?;
+
+        Ok(Stmt::Import {
+            keyword,
+            path,
+            name,
+        })
+    }
+
+    fn class_declaration(&self) -> Result<Stmt> {
+        let name = self
+            .consume(&TokenType::Identifier, "Expect class name")?
+            .clone();
+        self.consume(&TokenType::LeftBrace, "Expect '{' before class body")?;
+
+        let mut methods = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+
+        self.consume(&TokenType::RightBrace, "Expect '}' after class body")?;
+        Ok(Stmt::ClassDecl { name, methods })
+    }
+
+    fn function(&self, kind: &str) -> Result<Rc<FunctionDecl>> {
         let name = self
             .consume(&TokenType::Identifier, &format!("Expect {} name", kind))?
             .clone();
@@ -152,13 +343,21 @@ impl Parser {
             &TokenType::LeftParen,
             &format!("Expect '(' after {} name", kind),
         )?;
+        self.function_body(Some(name), kind)
+    }
 
+    /// Parses the parameters and body of a function, after the opening '('
+    fn function_body(&self, name: Option<Token>, kind: &str) -> Result<Rc<FunctionDecl>> {
         let mut params = vec![];
         if !self.check(&TokenType::RightParen) {
             let mut first = true;
             while first || self.consume_matching(&[TokenType::Comma]).is_some() {
                 if params.len() >= 255 {
-                    make_error(self.peek(), "Can't have more than 255 parameters");
+                    return Err(parse_error(
+                        self.peek(),
+                        ErrorCode::TooManyParameters,
+                        "Can't have more than 255 parameters",
+                    ));
                 }
                 params.push(
                     self.consume(&TokenType::Identifier, "Expect parameter name")?
@@ -174,13 +373,23 @@ impl Parser {
         )?;
         let body = self.block()?;
 
-        Ok(Stmt::FunctionDecl(FunctionDecl { name, params, body }))
+        Ok(Rc::new(FunctionDecl { name, params, body }))
     }
 
     fn statement(&self) -> Result<Stmt> {
         // Similar to using consume_matching(), but using match. Need to make sure we
         // call advance manually though.
         match self.peek().token_type {
+            TokenType::Break => {
+                let keyword = self.advance().clone();
+                self.consume(&TokenType::Semicolon, "Expect ';' after 'break'")?;
+                Ok(Stmt::Break { keyword })
+            }
+            TokenType::Continue => {
+                let keyword = self.advance().clone();
+                self.consume(&TokenType::Semicolon, "Expect ';' after 'continue'")?;
+                Ok(Stmt::Continue { keyword })
+            }
             TokenType::For => {
                 self.advance();
                 self.for_statement()
@@ -198,8 +407,16 @@ impl Parser {
                 self.print_statement()
             }
             TokenType::Return => {
+                let keyword = self.advance().clone();
+                self.return_statement(keyword)
+            }
+            TokenType::Throw => {
+                let keyword = self.advance().clone();
+                self.throw_statement(keyword)
+            }
+            TokenType::Try => {
                 self.advance();
-                self.return_statement()
+                self.try_statement()
             }
             TokenType::While => {
                 self.advance();
@@ -237,18 +454,13 @@ impl Parser {
         };
         self.consume(&TokenType::RightParen, "Expect ')' after for clauses")?;
 
-        let mut body = self.statement()?;
+        let body = self.statement()?;
 
-        // Desugar to while loop
-        if let Some(increment) = increment {
-            // Replace the body with a little block that contains the original body followed
-            // by an expression statement that evaluates the increment
-            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
-        }
-
-        body = Stmt::While {
+        // Desugar to while loop, which evaluates the increment after each iteration
+        let mut body = Stmt::While {
             condition,
             body: Box::new(body),
+            increment,
         };
 
         if let Some(initializer) = initializer {
@@ -283,10 +495,14 @@ impl Parser {
         let condition = self.expression()?;
         self.consume(&TokenType::RightParen, "Expect ')' after if condition")?;
         let body = Box::new(self.statement()?);
-        Ok(Stmt::While { condition, body })
+        Ok(Stmt::While {
+            condition,
+            body,
+            increment: None,
+        })
     }
 
-    fn return_statement(&self) -> Result<Stmt> {
+    fn return_statement(&self, keyword: Token) -> Result<Stmt> {
         let value = if !self.check(&TokenType::Semicolon) {
             Some(self.expression()?)
         } else {
@@ -294,9 +510,56 @@ impl Parser {
         };
 
         self.consume(&TokenType::Semicolon, "Expect ';' after return value")?;
-        Ok(Stmt::Return {
-            value,
-            keyword: self.peek().clone(),
+        Ok(Stmt::Return { value, keyword })
+    }
+
+    fn throw_statement(&self, keyword: Token) -> Result<Stmt> {
+        let value = self.expression()?;
+        self.consume(&TokenType::Semicolon, "Expect ';' after thrown value")?;
+        Ok(Stmt::Throw { keyword, value })
+    }
+
+    fn try_statement(&self) -> Result<Stmt> {
+        self.consume(&TokenType::LeftBrace, "Expect '{' after 'try'")?;
+        let body = self.block()?;
+
+        let catch = if self.consume_matching(&[TokenType::Catch]).is_some() {
+            self.consume(&TokenType::LeftParen, "Expect '(' after 'catch'")?;
+            let name = self
+                .consume(&TokenType::Identifier, "Expect exception variable name")?
+                .clone();
+            self.consume(
+                &TokenType::RightParen,
+                "Expect ')' after exception variable",
+            )?;
+            self.consume(&TokenType::LeftBrace, "Expect '{' before catch body")?;
+            Some(CatchClause {
+                name,
+                body: self.block()?,
+            })
+        } else {
+            None
+        };
+
+        let finally = if self.consume_matching(&[TokenType::Finally]).is_some() {
+            self.consume(&TokenType::LeftBrace, "Expect '{' after 'finally'")?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err(parse_error(
+                self.peek(),
+                ErrorCode::ExpectedToken,
+                "Expect 'catch' or 'finally' after try block",
+            ));
+        }
+
+        Ok(Stmt::Try {
+            body,
+            catch,
+            finally,
         })
     }
 
@@ -326,193 +589,535 @@ impl Parser {
     }
 
     fn expression(&self) -> Result<Expr> {
-        self.assignment()
+        self.parse_precedence(Precedence::Assignment)
     }
 
-    fn assignment(&self) -> Result<Expr> {
-        let expr = self.or()?;
-
-        if let Some(equals) = self.consume_matching(&[TokenType::Equal]) {
-            let value = self.assignment()?;
-
-            match expr {
-                Expr::Variable { name } => {
-                    return Ok(Expr::Assign {
-                        name,
-                        value: Box::new(value),
-                    })
-                }
-                _ => {
-                    make_error(equals, "Invalid assignment target");
-                }
+    /// Parses any expression whose operators bind at least as tightly as
+    /// `precedence`.
+    fn parse_precedence(&self, precedence: Precedence) -> Result<Expr> {
+        let token = self.advance();
+        let prefix = match get_rule(&token.token_type).prefix {
+            Some(prefix) => prefix,
+            None => {
+                return Err(parse_error(
+                    token,
+                    ErrorCode::ExpectedExpression,
+                    "Expect expression",
+                ))
             }
+        };
+        let mut expr = prefix(self, token)?;
+
+        while precedence <= get_rule(&self.peek().token_type).precedence {
+            let token = self.advance();
+            // Every token with a precedence above None has an infix handler
+            let infix = get_rule(&token.token_type).infix.unwrap();
+            expr = infix(self, expr, token)?;
         }
+
         Ok(expr)
     }
 
-    fn or(&self) -> Result<Expr> {
-        let mut expr = self.and()?;
-        while let Some(operator) = self.consume_matching(&[TokenType::Or]) {
-            let right = Box::new(self.and()?);
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                operator: operator.token_type.clone(),
-                right,
-            };
-        }
-        Ok(expr)
+    fn grouping(&self, _token: &Token) -> Result<Expr> {
+        let expr = self.expression()?;
+        self.consume(&TokenType::RightParen, "Expect ')' after expression")?;
+        Ok(Expr::Grouping(Box::new(expr)))
     }
 
-    fn and(&self) -> Result<Expr> {
-        let mut expr = self.equality()?;
-        while let Some(operator) = self.consume_matching(&[TokenType::Or]) {
-            let right = Box::new(self.equality()?);
-            expr = Expr::Logical {
-                left: Box::new(expr),
-                operator: operator.token_type.clone(),
-                right,
-            };
-        }
-        Ok(expr)
+    fn literal(&self, token: &Token) -> Result<Expr> {
+        Ok(Expr::Literal(match &token.token_type {
+            TokenType::String(s) => Literal::String(Str::intern(s)),
+            // Only valid as the operand of `-`, which unary handles
+            TokenType::Integer(i64::MIN) => {
+                return Err(parse_error(
+                    token,
+                    ErrorCode::InvalidNumber,
+                    "Integer literal is too large",
+                )
+                .with_help(scanner::integer_too_large_help()))
+            }
+            TokenType::Integer(n) => Literal::Integer(*n),
+            TokenType::Number(n) => Literal::Number(*n),
+            TokenType::False => Literal::False,
+            TokenType::Nil => Literal::Nil,
+            TokenType::True => Literal::True,
+            _ => unreachable!(),
+        }))
     }
 
-    fn equality(&self) -> Result<Expr> {
-        let mut expr = self.comparison()?;
-        while let Some(operator) =
-            self.consume_matching(&[TokenType::BangEqual, TokenType::EqualEqual])
-        {
-            let right = Box::new(self.comparison()?);
-            expr = Expr::Logical {
-                left: Box::new(expr),
-                operator: operator.token_type.clone(),
-                right,
+    fn interpolation(&self, token: &Token) -> Result<Expr> {
+        let mut parts = vec![];
+        let mut segment = token;
+        loop {
+            let (TokenType::Interpolation(text) | TokenType::String(text)) = &segment.token_type
+            else {
+                unreachable!()
             };
+            if !text.is_empty() {
+                parts.push(Expr::Literal(Literal::String(Str::intern(text))));
+            }
+            if let TokenType::String(_) = segment.token_type {
+                return Ok(Expr::Interpolation(parts));
+            }
+
+            if is_continuation(self.peek()) {
+                return Err(parse_error(
+                    self.peek(),
+                    ErrorCode::EmptyInterpolation,
+                    "Expect expression inside '${}'",
+                ));
+            }
+            parts.push(self.expression()?);
+            segment = self.advance();
+            if !is_continuation(segment) {
+                return Err(parse_error(
+                    segment,
+                    ErrorCode::ExpectedToken,
+                    "Expect '}' after interpolated expression",
+                ));
+            }
         }
-        Ok(expr)
     }
 
-    fn comparison(&self) -> Result<Expr> {
-        let mut expr = self.term()?;
-        while let Some(operator) = self.consume_matching(&[
-            TokenType::Greater,
-            TokenType::GreaterEqual,
-            TokenType::Less,
-            TokenType::LessEqual,
-        ]) {
-            let right = Box::new(self.term()?);
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                operator: operator.token_type.clone(),
-                right,
-            };
-        }
-        Ok(expr)
+    fn variable(&self, token: &Token) -> Result<Expr> {
+        Ok(Expr::Variable {
+            id: self.next_id(token),
+            name: token.clone(),
+        })
     }
 
-    fn term(&self) -> Result<Expr> {
-        let mut expr = self.factor()?;
-        while let Some(operator) = self.consume_matching(&[TokenType::Minus, TokenType::Plus]) {
-            let right = Box::new(self.factor()?);
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                operator: operator.token_type.clone(),
-                right,
-            };
-        }
-        Ok(expr)
+    fn this(&self, token: &Token) -> Result<Expr> {
+        Ok(Expr::This {
+            id: self.next_id(token),
+            keyword: token.clone(),
+        })
     }
 
-    fn factor(&self) -> Result<Expr> {
-        let mut expr = self.unary()?;
-        while let Some(operator) = self.consume_matching(&[TokenType::Slash, TokenType::Star]) {
-            let right = Box::new(self.unary()?);
-            expr = Expr::Binary {
-                left: Box::new(expr),
-                operator: operator.token_type.clone(),
-                right,
-            };
+    fn unary(&self, token: &Token) -> Result<Expr> {
+        // i64::MIN can only be written negated, as its magnitude is too large to
+        // be an integer, so the negation is folded into the literal
+        if token.token_type == TokenType::Minus
+            && self.peek().token_type == TokenType::Integer(i64::MIN)
+        {
+            self.advance();
+            return Ok(Expr::Literal(Literal::Integer(i64::MIN)));
         }
-        Ok(expr)
+        // Parse at the same level to allow nesting, e.g. `!!a`
+        let right = Box::new(self.parse_precedence(Precedence::Unary)?);
+        Ok(Expr::Unary {
+            operator: token.clone(),
+            right,
+        })
     }
 
-    fn unary(&self) -> Result<Expr> {
-        if let Some(operator) = self.consume_matching(&[TokenType::Bang, TokenType::Minus]) {
-            let right = Box::new(self.unary()?);
-            return Ok(Expr::Unary {
-                operator: operator.token_type.clone(),
-                right,
-            });
-        }
-        self.call()
+    fn binary(&self, left: Expr, token: &Token) -> Result<Expr> {
+        // Parse the right operand one level higher so that binary operators are
+        // left-associative
+        let precedence = get_rule(&token.token_type).precedence.next();
+        let right = Box::new(self.parse_precedence(precedence)?);
+        Ok(Expr::Binary {
+            left: Box::new(left),
+            operator: token.clone(),
+            right,
+        })
     }
 
-    fn call(&self) -> Result<Expr> {
-        let mut expr = self.primary()?;
+    fn logical(&self, left: Expr, token: &Token) -> Result<Expr> {
+        let precedence = get_rule(&token.token_type).precedence.next();
+        let right = Box::new(self.parse_precedence(precedence)?);
+        Ok(Expr::Logical {
+            left: Box::new(left),
+            operator: token.clone(),
+            right,
+        })
+    }
 
-        loop {
-            if self.consume_matching(&[TokenType::LeftParen]).is_some() {
-                expr = self.finish_call(expr)?;
-            } else {
-                break;
-            }
-        }
+    fn assignment(&self, target: Expr, equals: &Token) -> Result<Expr> {
+        // Parse the value at the same level so that assignment is
+        // right-associative
+        let value = Box::new(self.parse_precedence(Precedence::Assignment)?);
 
-        Ok(expr)
+        match target {
+            Expr::Variable { id, name } => Ok(Expr::Assign { id, name, value }),
+            Expr::Get { object, name } => Ok(Expr::Set {
+                object,
+                name,
+                value,
+            }),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => Ok(Expr::IndexSet {
+                object,
+                bracket,
+                index,
+                value,
+            }),
+            _ => Err(parse_error(
+                equals,
+                ErrorCode::InvalidAssignmentTarget,
+                "Invalid assignment target",
+            )),
+        }
     }
 
-    fn finish_call(&self, callee: Expr) -> Result<Expr> {
+    fn call(&self, callee: Expr, paren: &Token) -> Result<Expr> {
         let mut arguments = vec![];
         if !self.check(&TokenType::RightParen) {
             let mut first = true;
             while first || self.consume_matching(&[TokenType::Comma]).is_some() {
                 if arguments.len() >= 255 {
-                    make_error(self.peek(), "Can't have more thant 255 arguments");
+                    return Err(parse_error(
+                        self.peek(),
+                        ErrorCode::TooManyArguments,
+                        "Can't have more than 255 arguments",
+                    ));
                 }
                 arguments.push(self.expression()?);
                 first = false;
             }
         }
 
-        let _paren = self.consume(&TokenType::RightParen, "Expect ')' after arguments")?;
+        self.consume(&TokenType::RightParen, "Expect ')' after arguments")?;
 
         Ok(Expr::Call {
             callee: Box::new(callee),
-            //paren,
+            paren: paren.clone(),
             arguments,
         })
     }
 
-    fn primary(&self) -> Result<Expr> {
-        let token = self.advance();
-        match &token.token_type {
-            TokenType::LeftParen => {
-                let expr = self.expression()?;
-                self.consume(&TokenType::RightParen, "Expect ')' after expression")?;
-                Ok(Expr::Grouping(Box::new(expr)))
-            }
-            TokenType::String(s) => Ok(Expr::Literal(Literal::String(s.clone()))),
-            TokenType::Number(n) => Ok(Expr::Literal(Literal::Number(*n))),
-            TokenType::False => Ok(Expr::Literal(Literal::False)),
-            TokenType::Nil => Ok(Expr::Literal(Literal::Nil)),
-            TokenType::True => Ok(Expr::Literal(Literal::True)),
-            TokenType::Identifier => Ok(Expr::Variable {
-                name: token.clone(),
-            }),
-            _ => Err(make_error(self.peek(), "Expect expression")),
+    fn list(&self, _token: &Token) -> Result<Expr> {
+        let mut elements = vec![];
+        if !self.check(&TokenType::RightBracket) {
+            let mut first = true;
+            while first || self.consume_matching(&[TokenType::Comma]).is_some() {
+                elements.push(self.expression()?);
+                first = false;
+            }
+        }
+        self.consume(&TokenType::RightBracket, "Expect ']' after list elements")?;
+        Ok(Expr::List(elements))
+    }
+
+    fn map(&self, brace: &Token) -> Result<Expr> {
+        let mut entries = vec![];
+        if !self.check(&TokenType::RightBrace) {
+            let mut first = true;
+            while first || self.consume_matching(&[TokenType::Comma]).is_some() {
+                let key = self.expression()?;
+                self.consume(&TokenType::Colon, "Expect ':' after map key")?;
+                let value = self.expression()?;
+                entries.push((key, value));
+                first = false;
+            }
         }
+        self.consume(&TokenType::RightBrace, "Expect '}' after map entries")?;
+        Ok(Expr::Map {
+            brace: brace.clone(),
+            entries,
+        })
+    }
+
+    fn index(&self, object: Expr, bracket: &Token) -> Result<Expr> {
+        let index = self.expression()?;
+        self.consume(&TokenType::RightBracket, "Expect ']' after index")?;
+        Ok(Expr::Index {
+            object: Box::new(object),
+            bracket: bracket.clone(),
+            index: Box::new(index),
+        })
+    }
+
+    fn lambda(&self, _token: &Token) -> Result<Expr> {
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'fun'")?;
+        Ok(Expr::Lambda(self.function_body(None, "function")?))
+    }
+
+    fn dot(&self, object: Expr, _token: &Token) -> Result<Expr> {
+        let name = self
+            .consume(&TokenType::Identifier, "Expect property name after '.'")?
+            .clone();
+        Ok(Expr::Get {
+            object: Box::new(object),
+            name,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{ast::stmt::Stmt, parser::Parser, scanner::scan_tokens};
+    use crate::{
+        ast::{
+            expr::{Expr, Literal},
+            stmt::Stmt,
+        },
+        error::ErrorCode,
+        parser::Parser,
+        runtime::value::Value,
+        scanner::scan_tokens,
+        source::SourceId,
+    };
 
     #[test]
     fn parse() {
         let input = "print (1 + 2 * -3 - 4);";
-        let tokens = scan_tokens(input).unwrap();
+        let tokens = scan_tokens(input, SourceId::default()).unwrap();
         let parser = Parser::new(tokens);
         let statements = parser.parse().unwrap();
         assert!(matches!(statements[0], Stmt::Print(_)));
     }
+
+    // Renders with explicit parentheses so the shape of the tree is visible
+    fn render(expr: &Expr) -> String {
+        match expr {
+            Expr::Assign { name, value, .. } => format!("(= {} {})", name.lexeme, render(value)),
+            Expr::Binary {
+                left,
+                operator: op,
+                right,
+            }
+            | Expr::Logical {
+                left,
+                operator: op,
+                right,
+            } => format!("({} {} {})", op.lexeme, render(left), render(right)),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                let mut s = format!("(call {}", render(callee));
+                for argument in arguments {
+                    s.push(' ');
+                    s.push_str(&render(argument));
+                }
+                s.push(')');
+                s
+            }
+            Expr::Get { object, name } => format!("(. {} {})", render(object), name.lexeme),
+            Expr::Grouping(expr) => format!("(group {})", render(expr)),
+            Expr::Lambda(decl) => {
+                let params: Vec<&str> = decl.params.iter().map(|p| p.lexeme.as_str()).collect();
+                format!("(fun ({}))", params.join(" "))
+            }
+            Expr::Index { object, index, .. } => {
+                format!("([] {} {})", render(object), render(index))
+            }
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => format!(
+                "(= ([] {} {}) {})",
+                render(object),
+                render(index),
+                render(value)
+            ),
+            Expr::Interpolation(parts) => {
+                let parts: Vec<String> = parts.iter().map(render).collect();
+                format!("(str {})", parts.join(" "))
+            }
+            Expr::List(elements) => {
+                let elements: Vec<String> = elements.iter().map(render).collect();
+                format!("(list {})", elements.join(" "))
+            }
+            Expr::Map { entries, .. } => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("({} {})", render(key), render(value)))
+                    .collect();
+                format!("(map {})", entries.join(" "))
+            }
+            Expr::Literal(Literal::Integer(n)) => n.to_string(),
+            Expr::Literal(Literal::Number(n)) => Value::Number(n.0).to_string(),
+            Expr::Literal(literal) => format!("{:?}", literal),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => format!(
+                "(= (. {} {}) {})",
+                render(object),
+                name.lexeme,
+                render(value)
+            ),
+            Expr::This { .. } => "this".to_string(),
+            Expr::Unary {
+                operator: op,
+                right,
+            } => format!("({} {})", op.lexeme, render(right)),
+            Expr::Variable { name, .. } => name.lexeme.clone(),
+        }
+    }
+
+    fn parse_expression(input: &str) -> String {
+        let tokens = scan_tokens(&format!("{};", input), SourceId::default()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        match &statements[..] {
+            [Stmt::Expression(expr)] => render(expr),
+            _ => panic!("'{}' did not parse as a single expression", input),
+        }
+    }
+
+    #[test]
+    fn binary_operators_are_left_associative() {
+        for op in [
+            "*", "/", "%", "+", "-", ">", ">=", "<", "<=", "==", "!=", "and", "or",
+        ] {
+            assert_eq!(
+                parse_expression(&format!("a {op} b {op} c")),
+                format!("({op} ({op} a b) c)")
+            );
+        }
+    }
+
+    #[test]
+    fn unary_operators_nest() {
+        assert_eq!(parse_expression("!!a"), "(! (! a))");
+        assert_eq!(parse_expression("--a"), "(- (- a))");
+        assert_eq!(parse_expression("-!a"), "(- (! a))");
+    }
+
+    #[test]
+    fn assignment_is_right_associative() {
+        assert_eq!(parse_expression("a = b = c"), "(= a (= b c))");
+        assert_eq!(
+            parse_expression("a.x = b.y = c"),
+            "(= (. a x) (= (. b y) c))"
+        );
+    }
+
+    #[test]
+    fn precedence_levels() {
+        // Each operator should bind tighter than every operator on a looser level
+        let levels = [
+            vec!["or"],
+            vec!["and"],
+            vec!["==", "!="],
+            vec![">", ">=", "<", "<="],
+            vec!["+", "-"],
+            vec!["*", "/"],
+        ];
+        for (i, looser) in levels.iter().enumerate() {
+            for tighter in levels.iter().skip(i + 1) {
+                for low in looser {
+                    for high in tighter {
+                        assert_eq!(
+                            parse_expression(&format!("a {low} b {high} c")),
+                            format!("({low} a ({high} b c))")
+                        );
+                        assert_eq!(
+                            parse_expression(&format!("a {high} b {low} c")),
+                            format!("({low} ({high} a b) c)")
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unary_binds_tighter_than_binary() {
+        assert_eq!(parse_expression("-a * b"), "(* (- a) b)");
+        assert_eq!(parse_expression("!a == b"), "(== (! a) b)");
+        assert_eq!(parse_expression("a - -b"), "(- a (- b))");
+    }
+
+    #[test]
+    fn call_and_get_bind_tightest() {
+        assert_eq!(parse_expression("-a.b"), "(- (. a b))");
+        assert_eq!(parse_expression("!f()"), "(! (call f))");
+        assert_eq!(
+            parse_expression("a.b(c + d).e"),
+            "(. (call (. a b) (+ c d)) e)"
+        );
+        assert_eq!(parse_expression("f(a)(b)"), "(call (call f a) b)");
+        assert_eq!(parse_expression("-a[b]"), "(- ([] a b))");
+        assert_eq!(parse_expression("a.b[c](d)"), "(call ([] (. a b) c) d)");
+        assert_eq!(parse_expression("a[b][c]"), "([] ([] a b) c)");
+    }
+
+    #[test]
+    fn assignment_binds_loosest() {
+        assert_eq!(parse_expression("a = b or c"), "(= a (or b c))");
+        assert_eq!(parse_expression("a.b = 1 + 2"), "(= (. a b) (+ 1 2))");
+    }
+
+    #[test]
+    fn lambda_is_a_primary_expression() {
+        assert_eq!(parse_expression("f(fun (a, b) {})"), "(call f (fun (a b)))");
+        assert_eq!(parse_expression("fun () {}()"), "(call (fun ()))");
+        assert_eq!(parse_expression("x = fun (a) {}"), "(= x (fun (a)))");
+    }
+
+    #[test]
+    fn grouping_overrides_precedence() {
+        assert_eq!(parse_expression("(a + b) * c"), "(* (group (+ a b)) c)");
+        assert_eq!(parse_expression("a - (b - c)"), "(- a (group (- b c)))");
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse_expression("1 + 2.5"), "(+ 1 2.5)");
+        assert_eq!(parse_expression("true != nil"), "(!= true nil)");
+        assert_eq!(parse_expression("\"a\" + this"), "(+ \"a\" this)");
+        assert_eq!(
+            parse_expression("[1, a + b, []]"),
+            "(list 1 (+ a b) (list ))"
+        );
+        assert_eq!(
+            parse_expression("x = {\"a\": 1, b: c or d}"),
+            "(= x (map (\"a\" 1) (b (or c d))))"
+        );
+        assert_eq!(
+            parse_expression("\"a${b + 1}${\"c${d}\"}\""),
+            "(str \"a\" (+ b 1) (str \"c\" d))"
+        );
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        for input in ["a + b = c;", "-a = b;", "f() = a;", "(a) = b;"] {
+            let tokens = scan_tokens(input, SourceId::default()).unwrap();
+            let errors = Parser::new(tokens).parse().unwrap_err();
+            assert_eq!(errors[0].code(), ErrorCode::InvalidAssignmentTarget);
+        }
+    }
+
+    #[test]
+    fn smallest_integer_is_written_negated() {
+        assert_eq!(
+            parse_expression("-9223372036854775808 - 1"),
+            "(- -9223372036854775808 1)"
+        );
+        assert_eq!(
+            parse_expression("- -9223372036854775808"),
+            "(- -9223372036854775808)"
+        );
+        for input in ["9223372036854775808;", "1 - 9223372036854775808;"] {
+            let tokens = scan_tokens(input, SourceId::default()).unwrap();
+            let errors = Parser::new(tokens).parse().unwrap_err();
+            assert_eq!(errors[0].code(), ErrorCode::InvalidNumber);
+        }
+    }
+
+    #[test]
+    fn identical_expressions_get_distinct_ids() {
+        let tokens = scan_tokens("a = a; a = a;", SourceId(3)).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let ids: Vec<_> = statements
+            .iter()
+            .flat_map(|statement| match statement {
+                Stmt::Expression(Expr::Assign { id, value, .. }) => match value.as_ref() {
+                    Expr::Variable { id: value_id, .. } => [*id, *value_id],
+                    _ => panic!("Expected a variable"),
+                },
+                _ => panic!("Expected an assignment"),
+            })
+            .collect();
+        assert!(ids.iter().all(|id| id.source == SourceId(3)));
+        let indices: Vec<_> = ids.iter().map(|id| id.index).collect();
+        assert_eq!(indices, [0, 1, 2, 3]);
+    }
 }
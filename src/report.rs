@@ -0,0 +1,163 @@
+use std::fmt::Write;
+
+use crate::{error::Error, source::SourceMap};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders an error compiler-style, quoting the source line it points at with
+/// a caret underneath the offending span:
+///
+/// ```text
+/// error[E401]: Operand must be a number.
+///  --> script.lox:1:7
+///   |
+/// 1 | print -"a";
+///   |       ^
+/// ```
+pub fn render(error: &Error, sources: &SourceMap, colour: bool) -> String {
+    let paint = |style: &str, text: &str| {
+        if colour {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    };
+
+    let diagnostic = error.diagnostic();
+    let span = diagnostic.span;
+    let source = sources.get(span.source);
+    let gutter = " ".repeat(span.line.to_string().len());
+
+    let mut out = String::new();
+    let heading = format!("error[{}]", diagnostic.code);
+    writeln!(
+        out,
+        "{}{}",
+        paint(RED, &heading),
+        paint(BOLD, &format!(": {}", diagnostic.message))
+    )
+    .unwrap();
+
+    let location = match source {
+        Some(source) => format!("{}:{}:{}", source.name, span.line, span.col),
+        None => format!("{}:{}", span.line, span.col),
+    };
+    writeln!(out, "{}{} {}", gutter, paint(BLUE, "-->"), location).unwrap();
+
+    if let Some(source) = source {
+        let (line, line_start) = source.line_at(span.start);
+        // Keep any tabs before the span so the caret lines up with it
+        let padding: String = line
+            .get(..span.start - line_start)
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source
+            .text
+            .get(span.start..span.end.min(line_start + line.len()))
+            .map_or(0, |s| s.chars().count())
+            .max(1);
+        let bar = paint(BLUE, "|");
+
+        writeln!(out, "{} {}", gutter, bar).unwrap();
+        writeln!(
+            out,
+            "{} {} {}",
+            paint(BLUE, &span.line.to_string()),
+            bar,
+            line
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{} {} {}{}",
+            gutter,
+            bar,
+            padding,
+            paint(RED, &"^".repeat(width))
+        )
+        .unwrap();
+    }
+
+    for note in &diagnostic.notes {
+        writeln!(out, "{} {} note: {}", gutter, paint(BLUE, "="), note).unwrap();
+    }
+    if let Some(help) = &diagnostic.help {
+        writeln!(out, "{} {} help: {}", gutter, paint(BLUE, "="), help).unwrap();
+    }
+    if !diagnostic.backtrace.is_empty() {
+        writeln!(out, "backtrace, most recent call first:").unwrap();
+        // Deep recursion repeats the same frame thousands of times
+        let runs = diagnostic
+            .backtrace
+            .chunk_by(|a, b| a.function == b.function && a.call_site == b.call_site);
+        for run in runs {
+            let frame = &run[0];
+            let site = frame.call_site;
+            let file = sources.get(site.source).map_or("<unknown>", |s| &s.name);
+            writeln!(
+                out,
+                "    {} called at {}:{}:{}",
+                paint(BOLD, &frame.function),
+                file,
+                site.line,
+                site.col
+            )
+            .unwrap();
+            if run.len() > 1 {
+                writeln!(out, "    ... repeated {} more times", run.len() - 1).unwrap();
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::{error::ErrorCode, parser::Parser, scanner::scan_tokens, source::SourceMap};
+
+    #[test]
+    fn snippet() {
+        let mut sources = SourceMap::default();
+        let source = "var a = 1;\nprint a +;\n";
+        let id = sources.add("test.lox", source);
+        let tokens = scan_tokens(source, id).unwrap();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(errors[0].code(), ErrorCode::ExpectedExpression);
+
+        assert_eq!(
+            render(&errors[0], &sources, false),
+            "error[E202]: Expect expression at ';'
+ --> test.lox:2:10
+  |
+2 | print a +;
+  |          ^
+"
+        );
+    }
+
+    #[test]
+    fn help_and_tabs() {
+        let mut sources = SourceMap::default();
+        let source = "\tprint \"abc";
+        let id = sources.add("test.lox", source);
+        let error = scan_tokens(source, id).unwrap_err();
+
+        assert_eq!(
+            render(&error, &sources, false),
+            "error[E102]: Unterminated string
+ --> test.lox:1:8
+  |
+1 | \tprint \"abc
+  | \t      ^^^^
+  = help: add a closing '\"' to end the string
+"
+        );
+    }
+}
@@ -2,36 +2,91 @@ use std::collections::HashMap;
 
 use crate::{
     ast::{
-        expr::Expr,
+        expr::{Expr, ExprId},
         stmt::{FunctionDecl, Stmt},
     },
-    error::report_error,
-    runtime::interpreter::Interpreter,
+    error::{resolve_error, Error, ErrorCode},
     scanner::Token,
 };
 
+/// Where each local variable expression finds its variable, by the id of the
+/// expression. Expressions which aren't present refer to globals.
+pub type Locals = HashMap<ExprId, Slot>;
+
+/// Where a local variable lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    /// How many scopes out from its use the variable was declared
+    pub depth: u32,
+    /// The variable's position amongst those declared in that scope
+    pub index: usize,
+}
+
+/// A local variable in a scope being resolved
+struct Variable {
+    index: usize,
+    is_defined: bool,
+}
+
 #[derive(PartialEq)]
 enum FunctionType {
     None,
     Function,
+    Initializer,
+    Method,
 }
 
-pub struct Resolver<'a> {
-    interpreter: &'a mut Interpreter,
-    scopes: Vec<HashMap<String, bool>>,
+#[derive(PartialEq)]
+enum ClassType {
+    None,
+    Class,
+}
+
+#[derive(PartialEq)]
+enum LoopType {
+    None,
+    Loop,
+}
+
+pub struct Resolver {
+    locals: Locals,
+    scopes: Vec<HashMap<String, Variable>>,
     current_function: FunctionType,
+    current_class: ClassType,
+    current_loop: LoopType,
+    errors: Vec<Error>,
 }
 
-impl<'a> Resolver<'a> {
-    pub fn new(interpreter: &mut Interpreter) -> Resolver {
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
         Resolver {
-            interpreter,
+            locals: HashMap::new(),
             scopes: vec![],
             current_function: FunctionType::None,
+            current_class: ClassType::None,
+            current_loop: LoopType::None,
+            errors: vec![],
+        }
+    }
+
+    /// Resolves every variable in `statements`, returning all of the static
+    /// errors found
+    pub fn resolve(mut self, statements: &[Stmt]) -> Result<Locals, Vec<Error>> {
+        self.resolve_statements(statements);
+        if self.errors.is_empty() {
+            Ok(self.locals)
+        } else {
+            Err(self.errors)
         }
     }
 
-    pub fn resolve_statements(&mut self, statements: &[Stmt]) {
+    fn resolve_statements(&mut self, statements: &[Stmt]) {
         for statement in statements {
             self.resolve_statement(statement);
         }
@@ -44,14 +99,70 @@ impl<'a> Resolver<'a> {
                 self.resolve_statements(statements);
                 self.end_scope();
             }
+            Stmt::Break { keyword } => {
+                if self.current_loop == LoopType::None {
+                    self.error(
+                        keyword,
+                        ErrorCode::BreakOutsideLoop,
+                        "Can't use 'break' outside of a loop",
+                    );
+                }
+            }
+            Stmt::ClassDecl { name, methods } => {
+                let enclosing_class = std::mem::replace(&mut self.current_class, ClassType::Class);
+                self.declare(name);
+                self.define(name);
+
+                self.begin_scope();
+                let this = Variable {
+                    index: 0,
+                    is_defined: true,
+                };
+                if let Some(top) = self.scopes.last_mut() {
+                    top.insert("this".to_string(), this);
+                }
+                for method in methods {
+                    let declaration = if method.declared_name().lexeme == "init" {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    self.resolve_function(method, declaration);
+                }
+                self.end_scope();
+
+                self.current_class = enclosing_class;
+            }
+            Stmt::Continue { keyword } => {
+                if self.current_loop == LoopType::None {
+                    self.error(
+                        keyword,
+                        ErrorCode::ContinueOutsideLoop,
+                        "Can't use 'continue' outside of a loop",
+                    );
+                }
+            }
             Stmt::Expression(expression) => {
                 self.resolve_expression(expression);
             }
             Stmt::FunctionDecl(decl) => {
-                self.declare(&decl.name);
-                self.define(&decl.name);
+                self.declare(decl.declared_name());
+                self.define(decl.declared_name());
                 self.resolve_function(decl, FunctionType::Function);
             }
+            Stmt::Import { keyword, name, .. } => {
+                if !self.scopes.is_empty() || self.current_function != FunctionType::None {
+                    self.error(
+                        keyword,
+                        ErrorCode::ImportOutsideTopLevel,
+                        "Can only import at the top level",
+                    );
+                }
+                if let Some(name) = name {
+                    self.declare(name);
+                    self.define(name);
+                }
+            }
             Stmt::If {
                 condition,
                 then_branch,
@@ -69,14 +180,64 @@ impl<'a> Resolver<'a> {
             Stmt::Return { keyword, value } => {
                 if let Some(expression) = value {
                     if self.current_function == FunctionType::None {
-                        report_error(keyword, "Can't return from top-level code");
+                        self.error(
+                            keyword,
+                            ErrorCode::ReturnFromTopLevel,
+                            "Can't return from top-level code",
+                        );
+                    }
+                    if self.current_function == FunctionType::Initializer {
+                        let error = resolve_error(
+                            keyword,
+                            ErrorCode::ReturnFromInitializer,
+                            "Can't return a value from an initializer",
+                        );
+                        self.errors.push(
+                            error.with_help(
+                                "initializers always return 'this', use a bare 'return;'",
+                            ),
+                        );
                     }
                     self.resolve_expression(expression);
                 }
             }
-            Stmt::While { condition, body } => {
+            Stmt::Throw { keyword: _, value } => {
+                self.resolve_expression(value);
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.begin_scope();
+                self.resolve_statements(body);
+                self.end_scope();
+                if let Some(catch) = catch {
+                    // The exception variable shares a scope with the catch body
+                    self.begin_scope();
+                    self.declare(&catch.name);
+                    self.define(&catch.name);
+                    self.resolve_statements(&catch.body);
+                    self.end_scope();
+                }
+                if let Some(finally) = finally {
+                    self.begin_scope();
+                    self.resolve_statements(finally);
+                    self.end_scope();
+                }
+            }
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
                 self.resolve_expression(condition);
+                let enclosing_loop = std::mem::replace(&mut self.current_loop, LoopType::Loop);
                 self.resolve_statement(body);
+                self.current_loop = enclosing_loop;
+                if let Some(increment) = increment {
+                    self.resolve_expression(increment);
+                }
             }
             Stmt::VarDecl { name, initializer } => {
                 self.declare(name);
@@ -90,9 +251,9 @@ impl<'a> Resolver<'a> {
 
     fn resolve_expression(&mut self, expression: &Expr) {
         match expression {
-            Expr::Assign { name, value } => {
+            Expr::Assign { id, name, value } => {
                 self.resolve_expression(value);
-                self.resolve_local(expression, name);
+                self.resolve_local(*id, name);
             }
             Expr::Binary {
                 left,
@@ -102,17 +263,56 @@ impl<'a> Resolver<'a> {
                 self.resolve_expression(left);
                 self.resolve_expression(right);
             }
-            Expr::Call { callee, arguments } => {
+            Expr::Call {
+                callee,
+                paren: _,
+                arguments,
+            } => {
                 self.resolve_expression(callee);
 
                 for argument in arguments {
                     self.resolve_expression(argument);
                 }
             }
+            Expr::Get { object, name: _ } => {
+                self.resolve_expression(object);
+            }
             Expr::Grouping(expression) => {
                 self.resolve_expression(expression);
             }
+            Expr::Lambda(decl) => {
+                self.resolve_function(decl, FunctionType::Function);
+            }
+            Expr::Index {
+                object,
+                bracket: _,
+                index,
+            } => {
+                self.resolve_expression(object);
+                self.resolve_expression(index);
+            }
+            Expr::IndexSet {
+                object,
+                bracket: _,
+                index,
+                value,
+            } => {
+                self.resolve_expression(value);
+                self.resolve_expression(object);
+                self.resolve_expression(index);
+            }
+            Expr::Interpolation(parts) | Expr::List(parts) => {
+                for part in parts {
+                    self.resolve_expression(part);
+                }
+            }
             Expr::Literal(_) => {}
+            Expr::Map { brace: _, entries } => {
+                for (key, value) in entries {
+                    self.resolve_expression(key);
+                    self.resolve_expression(value);
+                }
+            }
             Expr::Logical {
                 left,
                 operator: _,
@@ -121,26 +321,53 @@ impl<'a> Resolver<'a> {
                 self.resolve_expression(left);
                 self.resolve_expression(right);
             }
+            Expr::Set {
+                object,
+                name: _,
+                value,
+            } => {
+                self.resolve_expression(value);
+                self.resolve_expression(object);
+            }
+            Expr::This { id, keyword } => {
+                if self.current_class == ClassType::None {
+                    self.error(
+                        keyword,
+                        ErrorCode::ThisOutsideClass,
+                        "Can't use 'this' outside of a class",
+                    );
+                    return;
+                }
+                self.resolve_local(*id, keyword);
+            }
             Expr::Unary { operator: _, right } => {
                 self.resolve_expression(right);
             }
-            Expr::Variable { name } => {
+            Expr::Variable { id, name } => {
                 if let Some(top) = self.scopes.last() {
-                    if let Some(is_defined) = top.get(&name.lexeme) {
-                        if is_defined == &false {
-                            report_error(name, "Can't read local variable in its own initializer");
+                    if let Some(variable) = top.get(&name.lexeme) {
+                        if !variable.is_defined {
+                            self.error(
+                                name,
+                                ErrorCode::ReadInOwnInitializer,
+                                "Can't read local variable in its own initializer",
+                            );
                         }
                     }
                 }
-                self.resolve_local(expression, name);
+                self.resolve_local(*id, name);
             }
         }
     }
 
-    fn resolve_local(&mut self, expression: &Expr, name: &Token) {
+    fn resolve_local(&mut self, id: ExprId, name: &Token) {
         for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(&name.lexeme) {
-                self.interpreter.resolve(expression, i.try_into().unwrap());
+            if let Some(variable) = scope.get(&name.lexeme) {
+                let slot = Slot {
+                    depth: i.try_into().unwrap(),
+                    index: variable.index,
+                };
+                self.locals.insert(id, slot);
                 return;
             }
         }
@@ -148,6 +375,8 @@ impl<'a> Resolver<'a> {
 
     fn resolve_function(&mut self, decl: &FunctionDecl, func_type: FunctionType) {
         let enclosing_function = std::mem::replace(&mut self.current_function, func_type);
+        // Loops don't extend into function bodies
+        let enclosing_loop = std::mem::replace(&mut self.current_loop, LoopType::None);
         self.begin_scope();
         for name in &decl.params {
             self.declare(name);
@@ -155,9 +384,14 @@ impl<'a> Resolver<'a> {
         }
         self.resolve_statements(&decl.body);
         self.end_scope();
+        self.current_loop = enclosing_loop;
         self.current_function = enclosing_function;
     }
 
+    fn error(&mut self, token: &Token, code: ErrorCode, message: &str) {
+        self.errors.push(resolve_error(token, code, message));
+    }
+
     fn begin_scope(&mut self) {
         self.scopes.push(HashMap::new());
     }
@@ -166,18 +400,71 @@ impl<'a> Resolver<'a> {
         self.scopes.pop();
     }
 
+    /// Gives a local variable the next slot in its scope
     fn declare(&mut self, name: &Token) {
-        if let Some(top) = self.scopes.last_mut() {
-            if top.contains_key(&name.lexeme) {
-                report_error(name, "Already a variable with this name in this scope");
+        let declared = match self.scopes.last_mut() {
+            Some(top) if top.contains_key(&name.lexeme) => true,
+            Some(top) => {
+                let variable = Variable {
+                    index: top.len(),
+                    is_defined: false,
+                };
+                top.insert(name.lexeme.to_string(), variable);
+                false
             }
-            top.insert(name.lexeme.to_string(), false);
+            None => false,
+        };
+        if declared {
+            self.error(
+                name,
+                ErrorCode::AlreadyDeclared,
+                "Already a variable with this name in this scope",
+            );
         }
     }
 
     fn define(&mut self, name: &Token) {
-        if let Some(top) = self.scopes.last_mut() {
-            top.insert(name.lexeme.to_string(), true);
+        if let Some(variable) = self
+            .scopes
+            .last_mut()
+            .and_then(|top| top.get_mut(&name.lexeme))
+        {
+            variable.is_defined = true;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Resolver;
+    use crate::{parser::Parser, scanner::scan_tokens, source::SourceId};
+
+    /// Depth and index of every resolved local, in order
+    fn slots(source: &str) -> Vec<(u32, usize)> {
+        let tokens = scan_tokens(source, SourceId::default()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let locals = Resolver::new().resolve(&statements).unwrap();
+        let mut slots: Vec<_> = locals
+            .into_values()
+            .map(|slot| (slot.depth, slot.index))
+            .collect();
+        slots.sort();
+        slots
+    }
+
+    #[test]
+    fn locals_take_slots_in_declaration_order() {
+        assert_eq!(
+            slots("var g; { var a = 1; var b = 2; { var c = b; print a; print c; } }"),
+            vec![(0, 0), (1, 0), (1, 1)]
+        );
+    }
+
+    #[test]
+    fn this_comes_before_parameters() {
+        assert_eq!(
+            slots("class A { m(x, y) { return this.f(y); } }"),
+            vec![(0, 1), (1, 0)]
+        );
+    }
+}
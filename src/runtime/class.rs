@@ -0,0 +1,152 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use super::{
+    function::{Callable, Function},
+    gc::{self, Trace},
+    interpreter::Interpreter,
+    string::Str,
+    value::Value,
+};
+use crate::{
+    error::{Error, ErrorCode, Fault, Result},
+    vm::object::{BoundMethod, Closure},
+};
+
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, Method>,
+}
+
+/// A method as implemented by whichever backend declared the class
+pub enum Method {
+    Function(Function),
+    Closure(Rc<Closure>),
+}
+
+pub struct Instance {
+    class: Rc<Class>,
+    fields: HashMap<Str, Value>,
+}
+
+impl Class {
+    pub fn find_method(&self, name: &str) -> Option<&Method> {
+        self.methods.get(name)
+    }
+
+    /// Number of arguments a call to the class takes, which are passed to its
+    /// initializer
+    pub fn arity(&self) -> u8 {
+        self.find_method("init").map_or(0, Method::arity)
+    }
+}
+
+impl Method {
+    pub fn arity(&self) -> u8 {
+        match self {
+            Method::Function(function) => function.get_arity(),
+            Method::Closure(closure) => closure.function.arity,
+        }
+    }
+}
+
+// Implemented on the Rc so that new instances can share a pointer back to
+// their class
+impl Callable for Rc<Class> {
+    fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value> {
+        let instance = gc::instance(Instance::new(self.clone()));
+
+        match self.find_method("init") {
+            Some(Method::Function(initializer)) => {
+                initializer
+                    .bind(instance.clone())
+                    .call(interpreter, arguments)?;
+            }
+            Some(Method::Closure(_)) => unreachable!("Only the VM declares compiled methods"),
+            None => {}
+        }
+
+        Ok(Value::Instance(instance))
+    }
+
+    fn get_arity(&self) -> u8 {
+        self.arity()
+    }
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    // Takes the Rc rather than &self as methods need to be bound to the instance
+    pub fn get(instance: &Rc<RefCell<Instance>>, name: &str) -> Result<Value, Fault> {
+        let borrowed = instance.borrow();
+        if let Some(value) = borrowed.fields.get(name) {
+            return Ok(value.clone());
+        }
+
+        match borrowed.class.find_method(name) {
+            Some(Method::Function(method)) => {
+                return Ok(Value::Function(method.bind(instance.clone())));
+            }
+            Some(Method::Closure(method)) => {
+                return Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                    receiver: Value::Instance(instance.clone()),
+                    method: method.clone(),
+                })));
+            }
+            None => {}
+        }
+
+        Err(Fault::new(
+            ErrorCode::UndefinedProperty,
+            format!("Undefined property '{}'", name),
+        ))
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        self.fields.insert(Str::intern(name), value);
+    }
+}
+
+/// Converts an error into the value bound by a catch clause. Thrown values are
+/// caught as they are, while runtime errors become instances of `error_class`.
+pub fn caught_value(error: &Error, error_class: &Rc<Class>) -> Value {
+    if let Error::Thrown { value, .. } = error {
+        return value.as_ref().clone();
+    }
+
+    let mut instance = Instance::new(error_class.clone());
+    instance.set("message", Value::String(error.message().into()));
+    instance.set("line", Value::Integer(error.span().line as i64));
+    instance.set("code", Value::String(error.code().to_string().into()));
+    Value::Instance(gc::instance(instance))
+}
+
+impl Trace for Instance {
+    fn trace(&self, edges: &mut Vec<*const ()>) {
+        edges.push(gc::address(&self.class));
+        for value in self.fields.values() {
+            gc::trace_value(value, edges);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.fields.clear();
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format!("{} instance", self.class.name))
+    }
+}
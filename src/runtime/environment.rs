@@ -1,12 +1,18 @@
 use std::{cell::RefCell, collections::HashMap, ops::Deref, rc::Rc};
 
-use anyhow::{anyhow, Result};
-
-use super::value::Value;
+use super::{
+    gc::{self, Trace},
+    string::Str,
+    value::Value,
+};
+use crate::error::{ErrorCode, Fault};
 
+/// A scope of variables. Globals are kept by name in the outermost environment
+/// of a module, while locals are kept in the slots the resolver gave them.
 pub struct Environment {
     enclosing: Option<Rc<RefCell<Environment>>>,
-    values: HashMap<String, Option<Value>>,
+    values: HashMap<Str, Option<Value>>,
+    slots: Vec<Option<Value>>,
 }
 
 impl Environment {
@@ -14,6 +20,7 @@ impl Environment {
         Self {
             enclosing: None,
             values: HashMap::new(),
+            slots: vec![],
         }
     }
 
@@ -21,60 +28,117 @@ impl Environment {
         Self {
             enclosing: Some(enclosing),
             values: HashMap::new(),
+            slots: vec![],
         }
     }
 
+    /// Defines a variable, which for a local takes the next slot. Locals must
+    /// therefore be defined in the same order the resolver declared them.
     pub fn define(&mut self, name: &str, value: Option<Value>) {
-        self.values.insert(name.to_owned(), value);
+        match self.enclosing {
+            Some(_) => self.slots.push(value),
+            None => {
+                self.values.insert(Str::intern(name), value);
+            }
+        }
     }
 
-    pub fn assign(&mut self, name: &str, value: Value) -> Result<Value> {
-        if self.values.contains_key(name) {
-            self.values.insert(name.to_owned(), Some(value.clone()));
-            return Ok(value);
-        }
-        if let Some(enclosing) = &self.enclosing {
-            return enclosing.deref().borrow_mut().assign(name, value);
+    /// Assigns a global in this environment
+    pub fn assign(&mut self, name: &str, value: Value) -> Result<Value, Fault> {
+        match self.values.get_mut(name) {
+            Some(slot) => {
+                *slot = Some(value.clone());
+                Ok(value)
+            }
+            None => Err(undefined_variable(name)),
         }
+    }
 
-        Err(anyhow!("Undefined variable '{}'", name))
+    /// Gets a global from this environment
+    pub fn get(&self, name: &str) -> Result<Value, Fault> {
+        match self.values.get(name) {
+            Some(Some(value)) => Ok(value.clone()),
+            _ => Err(undefined_variable(name)),
+        }
     }
 
-    pub fn get(&self, name: &str) -> Result<Value> {
-        if let Some(got) = self.get_internal(name) {
-            return Ok(got.clone());
+    /// Gets a variable from the outermost environment, which holds the globals of
+    /// the module this environment belongs to
+    pub fn get_global(&self, name: &str) -> Result<Value, Fault> {
+        match &self.enclosing {
+            Some(enclosing) => enclosing.deref().borrow().get_global(name),
+            None => self.get(name),
         }
-        if let Some(enclosing) = &self.enclosing {
-            return enclosing.deref().borrow().get(name);
+    }
+
+    pub fn assign_global(&mut self, name: &str, value: Value) -> Result<Value, Fault> {
+        match &self.enclosing {
+            Some(enclosing) => enclosing.deref().borrow_mut().assign_global(name, value),
+            None => self.assign(name, value),
         }
-        Err(anyhow!("Undefined variable '{}'", name))
     }
 
-    pub fn get_at(&self, distance: u32, name: &str) -> Result<Value> {
-        if distance == 0 {
-            return self.get(name);
+    /// Gets the local in `slot` of the environment `depth` scopes out. `name` is
+    /// only used to report it being read before it's defined.
+    pub fn get_at(&self, depth: u32, slot: usize, name: &str) -> Result<Value, Fault> {
+        if depth == 0 {
+            return match self.slots.get(slot) {
+                Some(Some(value)) => Ok(value.clone()),
+                _ => Err(undefined_variable(name)),
+            };
         }
         // The unwrap here is safe if we trust our resolver
         self.enclosing
             .as_deref()
             .unwrap()
             .borrow()
-            .get_at(distance - 1, name)
+            .get_at(depth - 1, slot, name)
     }
 
-    pub fn assign_at(&mut self, distance: u32, name: &str, value: Value) -> Result<Value> {
-        if distance == 0 {
-            return self.assign(name, value);
+    pub fn assign_at(
+        &mut self,
+        depth: u32,
+        slot: usize,
+        name: &str,
+        value: Value,
+    ) -> Result<Value, Fault> {
+        if depth == 0 {
+            return match self.slots.get_mut(slot) {
+                Some(local) => {
+                    *local = Some(value.clone());
+                    Ok(value)
+                }
+                None => Err(undefined_variable(name)),
+            };
         }
         // The unwrap here is safe if we trust our resolver
         self.enclosing
             .as_deref()
             .unwrap()
             .borrow_mut()
-            .assign_at(distance - 1, name, value)
+            .assign_at(depth - 1, slot, name, value)
+    }
+}
+
+impl Trace for Environment {
+    fn trace(&self, edges: &mut Vec<*const ()>) {
+        edges.extend(self.enclosing.as_ref().map(gc::address));
+        for value in self.values.values().chain(&self.slots).flatten() {
+            gc::trace_value(value, edges);
+        }
     }
 
-    fn get_internal(&self, name: &str) -> Option<&Value> {
-        self.values.get(name)?.as_ref()
+    fn clear(&mut self) {
+        self.enclosing = None;
+        self.values.clear();
+        self.slots.clear();
     }
 }
+
+fn undefined_variable(name: &str) -> Fault {
+    Fault::new(
+        ErrorCode::UndefinedVariable,
+        format!("Undefined variable '{}'", name),
+    )
+    .with_help(format!("declare it with 'var {}' before using it", name))
+}
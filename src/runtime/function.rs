@@ -1,28 +1,73 @@
 use std::{cell::RefCell, fmt, rc::Rc};
 
-use anyhow::Result;
-
-use super::{environment::Environment, interpreter::Interpreter, value::Value};
-use crate::ast::stmt::FunctionDecl;
+use super::{
+    class::Instance, environment::Environment, gc, interpreter::Interpreter, value::Value,
+};
+use crate::{
+    ast::stmt::FunctionDecl,
+    error::{Fault, Result, Span},
+};
 
 #[derive(Clone)]
 pub struct Function {
-    pub declaration: FunctionDecl,
+    pub declaration: Rc<FunctionDecl>,
     pub closure: Rc<RefCell<Environment>>,
+    pub is_initializer: bool,
 }
 
 #[derive(Clone)]
 pub struct NativeFunction {
     pub arity: u8,
-    pub func: fn(interpreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value>,
+    pub func: fn(context: &mut dyn NativeContext, arguments: Vec<Value>) -> Result<Value, Fault>,
     pub name: String,
 }
 
+/// How deeply calls can be nested before the stack overflows, unless changed
+pub const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+/// A call which hasn't returned yet
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    /// Name of the function or class being called
+    pub function: String,
+    /// Where the call was made from
+    pub call_site: Span,
+}
+
+/// What native functions can see of the backend calling them
+pub trait NativeContext {
+    /// Calls currently being executed, innermost last, including the native
+    /// itself
+    fn call_stack(&self) -> Vec<CallFrame>;
+
+    /// Collects garbage now, rather than waiting for enough allocations
+    fn collect_garbage(&mut self) -> gc::Stats;
+}
+
 pub trait Callable {
     fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value>;
     fn get_arity(&self) -> u8;
 }
 
+impl Function {
+    pub fn name(&self) -> &str {
+        match &self.declaration.name {
+            Some(name) => &name.lexeme,
+            None => "anonymous",
+        }
+    }
+
+    pub fn bind(&self, instance: Rc<RefCell<Instance>>) -> Function {
+        let mut environment = Environment::with_enclosing(self.closure.clone());
+        environment.define("this", Some(Value::Instance(instance)));
+        Function {
+            declaration: self.declaration.clone(),
+            closure: gc::environment(environment),
+            is_initializer: self.is_initializer,
+        }
+    }
+}
+
 impl Callable for Function {
     fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value> {
         let mut environment = Environment::with_enclosing(self.closure.clone());
@@ -35,6 +80,12 @@ impl Callable for Function {
         interpreter.execute_block(&self.declaration.body, environment)?;
         let return_value = interpreter.return_value.clone();
         interpreter.return_value = old_return_value;
+
+        // An initializer always returns `this`, even from an early bare `return`
+        if self.is_initializer {
+            let this = self.closure.borrow().get_at(0, 0, "this");
+            return Ok(this.expect("Initializers are always bound to an instance"));
+        }
         Ok(return_value.unwrap_or(Value::Nil))
     }
 
@@ -43,19 +94,21 @@ impl Callable for Function {
     }
 }
 
-impl Callable for NativeFunction {
-    fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Value>) -> Result<Value> {
-        (self.func)(interpreter, arguments)
-    }
-
-    fn get_arity(&self) -> u8 {
-        self.arity
+// Natives don't implement Callable, as they fail with faults which the
+// interpreter locates at the call site
+impl NativeFunction {
+    pub fn call(
+        &self,
+        context: &mut dyn NativeContext,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Fault> {
+        (self.func)(context, arguments)
     }
 }
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&format!("<fun {}>", self.declaration.name.lexeme))
+        f.write_str(&format!("<fun {}>", self.name()))
     }
 }
 
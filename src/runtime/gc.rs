@@ -0,0 +1,350 @@
+//! A mark-and-sweep collector which owns every environment and object.
+//!
+//! Everything is allocated through this module, which keeps it alive until a
+//! collection finds it can no longer be reached. Values refer to objects
+//! through reference counted handles, so a handle can never dangle, but it's
+//! the collector which decides when objects die: reference counting alone
+//! can't free a closure stored in the environment it closes over, or an
+//! instance which refers back to itself.
+//!
+//! Collections only happen at safe points chosen by the backends, which pass
+//! in their roots: the globals, the current environment, the environments and
+//! values of calls in progress, and values in the middle of being evaluated.
+//! Everything reachable from the roots is marked, and every unmarked object is
+//! swept by clearing it, which breaks the cycles keeping it alive, and letting
+//! go of it.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use super::{
+    class::{Class, Instance, Method},
+    environment::Environment,
+    map::Map,
+    value::Value,
+};
+use crate::vm::object::{Closure, Upvalue};
+
+/// Number of allocations before the first collection
+const FIRST_COLLECTION: usize = 16384;
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+    // Kept apart from the heap as backends check it at every safe point
+    static COLLECTION_DUE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Identity of an object, which is the address of its allocation
+pub type Address = *const ();
+
+/// An object the collector can look inside of
+pub trait Trace {
+    /// Adds the address of every object this one holds a reference to
+    fn trace(&self, edges: &mut Vec<Address>);
+
+    /// Drops every reference this object holds. Objects which can't change
+    /// can't form a cycle on their own, so don't need to do anything.
+    fn clear(&mut self) {}
+}
+
+/// What the collector has done so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub collections: usize,
+    /// Objects which the collector has found to be garbage
+    pub freed: usize,
+    /// Objects which were still alive after the last collection, plus those
+    /// allocated since
+    pub tracked: usize,
+}
+
+enum Object {
+    Mutable(Rc<RefCell<dyn Trace>>),
+    Immutable(Rc<dyn Trace>),
+}
+
+#[derive(Default)]
+struct Heap {
+    objects: Vec<Object>,
+    next_collection: usize,
+    stats: Stats,
+}
+
+pub fn environment(environment: Environment) -> Rc<RefCell<Environment>> {
+    let environment = Rc::new(RefCell::new(environment));
+    track(Object::Mutable(environment.clone()));
+    environment
+}
+
+pub fn class(class: Class) -> Rc<Class> {
+    let class = Rc::new(class);
+    track(Object::Immutable(class.clone()));
+    class
+}
+
+pub fn instance(instance: Instance) -> Rc<RefCell<Instance>> {
+    let instance = Rc::new(RefCell::new(instance));
+    track(Object::Mutable(instance.clone()));
+    instance
+}
+
+pub fn list(elements: Vec<Value>) -> Rc<RefCell<Vec<Value>>> {
+    let list = Rc::new(RefCell::new(elements));
+    track(Object::Mutable(list.clone()));
+    list
+}
+
+pub fn map(entries: Map) -> Rc<RefCell<Map>> {
+    let map = Rc::new(RefCell::new(entries));
+    track(Object::Mutable(map.clone()));
+    map
+}
+
+pub fn closure(closure: Closure) -> Rc<Closure> {
+    let closure = Rc::new(closure);
+    track(Object::Immutable(closure.clone()));
+    closure
+}
+
+pub fn upvalue(upvalue: Upvalue) -> Rc<RefCell<Upvalue>> {
+    let upvalue = Rc::new(RefCell::new(upvalue));
+    track(Object::Mutable(upvalue.clone()));
+    upvalue
+}
+
+/// Whether enough has been allocated since the last collection that the
+/// backend should collect at its next safe point
+pub fn collection_due() -> bool {
+    COLLECTION_DUE.get()
+}
+
+/// Frees everything which can't be reached from `roots`, which must include
+/// every object the backend is holding on to outside of the heap
+pub fn collect(roots: Vec<Address>) -> Stats {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.collect(roots);
+        heap.stats
+    })
+}
+
+/// Adds the address of any object `value` refers to
+pub fn trace_value(value: &Value, edges: &mut Vec<Address>) {
+    match value {
+        Value::Function(function) => edges.push(address(&function.closure)),
+        Value::Closure(closure) => edges.push(address(closure)),
+        Value::Class(class) => edges.push(address(class)),
+        Value::Instance(instance) => edges.push(address(instance)),
+        Value::List(list) => edges.push(address(list)),
+        Value::Map(map) => edges.push(address(map)),
+        // Bound methods and modules aren't allocated here, but hold on to
+        // objects which are
+        Value::BoundMethod(bound) => {
+            trace_value(&bound.receiver, edges);
+            edges.push(address(&bound.method));
+        }
+        Value::Module(module) => edges.push(address(module.globals())),
+        Value::Nil
+        | Value::Boolean(_)
+        | Value::Integer(_)
+        | Value::Number(_)
+        | Value::String(_)
+        | Value::NativeFunction(_) => {}
+    }
+}
+
+pub fn address<T: ?Sized>(object: &Rc<T>) -> Address {
+    Rc::as_ptr(object) as Address
+}
+
+fn track(object: Object) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(object);
+        heap.stats.tracked += 1;
+        if heap.objects.len() >= heap.next_collection.max(FIRST_COLLECTION) {
+            COLLECTION_DUE.set(true);
+        }
+    })
+}
+
+impl Heap {
+    fn collect(&mut self, roots: Vec<Address>) {
+        let index: HashMap<Address, usize> = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.address(), i))
+            .collect();
+
+        // Objects are only borrowed mutably while they're being changed, which
+        // never spans a safe point. If one is anyway, it can't be looked inside,
+        // so collecting is put off rather than risk freeing what it refers to.
+        let mut edges = Vec::with_capacity(self.objects.len());
+        for object in &self.objects {
+            let mut addresses = vec![];
+            if !object.trace(&mut addresses) {
+                return;
+            }
+            edges.push(
+                addresses
+                    .iter()
+                    .filter_map(|edge| index.get(edge).copied())
+                    .collect::<Vec<usize>>(),
+            );
+        }
+
+        // Mark
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<usize> = roots
+            .iter()
+            .filter_map(|root| index.get(root).copied())
+            .collect();
+        while let Some(i) = pending.pop() {
+            if marked[i] {
+                continue;
+            }
+            marked[i] = true;
+            pending.extend(edges[i].iter().filter(|&&edge| !marked[edge]));
+        }
+
+        // Sweep
+        let objects = std::mem::take(&mut self.objects);
+        for (object, marked) in objects.into_iter().zip(marked) {
+            if marked {
+                self.objects.push(object);
+            } else {
+                object.clear();
+                self.stats.freed += 1;
+            }
+        }
+        self.stats.collections += 1;
+        self.stats.tracked = self.objects.len();
+        self.next_collection = self.objects.len() * 2;
+        COLLECTION_DUE.set(false);
+    }
+}
+
+impl Object {
+    fn address(&self) -> Address {
+        match self {
+            Object::Mutable(object) => address(object),
+            Object::Immutable(object) => address(object),
+        }
+    }
+
+    /// Finds the objects this one refers to, unless it's being changed
+    fn trace(&self, edges: &mut Vec<Address>) -> bool {
+        match self {
+            Object::Mutable(object) => match object.try_borrow() {
+                Ok(object) => object.trace(edges),
+                Err(_) => return false,
+            },
+            Object::Immutable(object) => object.trace(edges),
+        }
+        true
+    }
+
+    fn clear(&self) {
+        if let Object::Mutable(object) = self {
+            if let Ok(mut object) = object.try_borrow_mut() {
+                object.clear();
+            }
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, edges: &mut Vec<Address>) {
+        for method in self.methods.values() {
+            match method {
+                Method::Function(function) => edges.push(address(&function.closure)),
+                Method::Closure(closure) => edges.push(address(closure)),
+            }
+        }
+    }
+}
+
+impl Trace for Vec<Value> {
+    fn trace(&self, edges: &mut Vec<Address>) {
+        for element in self {
+            trace_value(element, edges);
+        }
+    }
+
+    fn clear(&mut self) {
+        Vec::clear(self);
+    }
+}
+
+impl Trace for Map {
+    fn trace(&self, edges: &mut Vec<Address>) {
+        // Keys are never objects
+        for value in self.values() {
+            trace_value(value, edges);
+        }
+    }
+
+    fn clear(&mut self) {
+        Map::clear(self);
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, edges: &mut Vec<Address>) {
+        edges.extend(self.upvalues.iter().map(address));
+        edges.push(address(&self.globals));
+    }
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, edges: &mut Vec<Address>) {
+        if let Upvalue::Closed(value) = self {
+            trace_value(value, edges);
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Upvalue::Closed(Value::Nil);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{address, collect, environment, list};
+    use crate::runtime::{environment::Environment, value::Value};
+
+    #[test]
+    fn frees_cycles() {
+        let freed = collect(vec![]).freed;
+        let outer = list(vec![]);
+        let inner = list(vec![Value::List(outer.clone())]);
+        outer.borrow_mut().push(Value::List(inner));
+        let weak = std::rc::Rc::downgrade(&outer);
+        drop(outer);
+
+        assert!(weak.upgrade().is_some());
+        assert_eq!(collect(vec![]).freed, freed + 2);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn keeps_whatever_is_reachable() {
+        let globals = environment(Environment::new());
+        let cycle = list(vec![]);
+        cycle.borrow_mut().push(Value::List(cycle.clone()));
+        globals
+            .borrow_mut()
+            .define("cycle", Some(Value::List(cycle.clone())));
+        drop(cycle);
+
+        collect(vec![address(&globals)]);
+        let Value::List(cycle) = globals.borrow().get("cycle").unwrap() else {
+            panic!("Expected a list");
+        };
+        assert_eq!(cycle.borrow().len(), 1);
+    }
+}
@@ -1,53 +1,140 @@
-use std::{
-    cell::RefCell,
-    collections::HashMap,
-    rc::Rc,
-    time::{SystemTime, UNIX_EPOCH},
-};
-
-use anyhow::{anyhow, Result};
+use std::{cell::RefCell, collections::HashMap, iter, rc::Rc};
 
-use super::{environment::Environment, function::Function, value::Value};
+use super::{
+    class::{caught_value, Class, Method},
+    environment::Environment,
+    function::{CallFrame, Function, NativeContext, DEFAULT_MAX_DEPTH},
+    gc,
+    loader::{Import, Loader},
+    map::MapKey,
+    module::Module,
+    native, ops,
+    value::Value,
+};
 use crate::{
     ast::{
-        expr::{Expr, Literal},
+        expr::{Expr, ExprId, Literal},
         stmt::Stmt,
     },
-    runtime::function::{Callable, NativeFunction},
-    scanner::{Number, TokenType},
+    error::{
+        runtime_error, stack_overflow_error, thrown_error, Error, ErrorCode, ErrorLocation, Fault,
+        Result, Span,
+    },
+    resolver::Locals,
+    runtime::function::Callable,
+    scanner::{Number, Token, TokenType},
+};
+
+/// Native stack the interpreter sets aside for each call it can nest. How much
+/// a call really takes depends on the code it runs, so the stack is checked
+/// as well. Unoptimised builds take several times as much.
+const STACK_PER_CALL: usize = if cfg!(debug_assertions) {
+    32 << 10
+} else {
+    8 << 10
 };
 
+/// Native stack kept spare beyond that for the calls, for the code run between
+/// checks and whatever ran before the interpreter started
+const STACK_RESERVE: usize = 8 << 20;
+
+/// Largest native stack the interpreter will ask to be run on
+const MAX_STACK_SIZE: usize = 1 << 31;
+
+/// How deeply calls can be nested at most, as each needs room on the native
+/// stack
+pub const MAX_DEPTH: usize = (MAX_STACK_SIZE - STACK_RESERVE) / STACK_PER_CALL;
+
+pub enum LoopControl {
+    Break,
+    Continue,
+}
+
+/// A call made by `return`, which is made once the function returning it has
+/// so that it doesn't need a native stack frame of its own
+struct TailCall {
+    callee: Value,
+    arguments: Vec<Value>,
+    paren: Span,
+}
+
 pub struct Interpreter {
+    // Globals of the module currently being executed
     pub globals: Rc<RefCell<Environment>>,
     environment: Rc<RefCell<Environment>>,
-    locals: HashMap<Expr, u32>,
+    // Environments put aside by the blocks, calls and imports being executed,
+    // innermost last
+    scopes: Vec<Rc<RefCell<Environment>>>,
+    // Values which have been evaluated but not used yet, such as the operands
+    // evaluated before the last, which the collector has to be able to find
+    temporaries: Vec<Value>,
+    locals: Locals,
     // Used to unwind call stack when nested return is called
     pub return_value: Option<Value>,
+    // Used to unwind to the innermost loop when break or continue is called
+    loop_control: Option<LoopControl>,
+    // Made in place of the call being unwound by return_value
+    tail_call: Option<TailCall>,
+    // Try statements being executed by the innermost call, which calls can't
+    // return from as tail calls as they'd escape the try
+    tries: usize,
+    // Class of the values runtime errors are caught as
+    error_class: Rc<Class>,
+    loader: Loader,
+    // Calls currently being executed, innermost last
+    call_stack: Vec<CallFrame>,
+    // Calls can't nest any deeper than this
+    max_depth: usize,
+    // Calls can't be made once the native stack has grown down past this
+    // address, which is zero until the depth is set
+    stack_limit: usize,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
-        let mut globals = Environment::new();
-        globals.define(
-            "clock",
-            Some(Value::NativeFunction(NativeFunction {
-                arity: 0,
-                func: |_, _| {
-                    let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                    Ok(Value::Number(since_the_epoch.as_secs_f64()))
-                },
-                name: "clock".to_string(),
-            })),
-        );
-        let globals = Rc::new(RefCell::new(globals));
+        let globals = gc::environment(native::new_globals());
         Self {
             environment: globals.clone(),
             globals,
+            scopes: vec![],
+            temporaries: vec![],
             return_value: None,
+            loop_control: None,
+            tail_call: None,
+            tries: 0,
+            error_class: Rc::new(Class {
+                name: "Error".to_string(),
+                methods: HashMap::new(),
+            }),
             locals: HashMap::new(),
+            loader: Loader::default(),
+            call_stack: vec![],
+            max_depth: DEFAULT_MAX_DEPTH,
+            stack_limit: 0,
         }
     }
 
+    /// Size of the native stack the interpreter needs to nest calls
+    /// `max_depth` deep, which must be at most MAX_DEPTH
+    pub fn stack_size(max_depth: usize) -> usize {
+        max_depth * STACK_PER_CALL + STACK_RESERVE
+    }
+
+    /// Sets how deeply calls can be nested. Must be called from the top of a
+    /// native stack of at least `stack_size(max_depth)`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+        self.stack_limit = stack_address().saturating_sub(max_depth.saturating_mul(STACK_PER_CALL));
+    }
+
+    pub fn loader(&self) -> &Loader {
+        &self.loader
+    }
+
+    pub fn loader_mut(&mut self) -> &mut Loader {
+        &mut self.loader
+    }
+
     pub fn interpret(&mut self, statements: &[Stmt]) -> Result<()> {
         for statement in statements {
             self.execute(statement)?;
@@ -56,16 +143,55 @@ impl Interpreter {
     }
 
     pub fn execute(&mut self, statement: &Stmt) -> Result<()> {
-        if self.return_value.is_some() {
+        if self.return_value.is_some() || self.loop_control.is_some() {
             // Unwind stack
             return Ok(());
         }
+        // Between statements, every value in use is in an environment or kept as
+        // a temporary, so it's safe to collect
+        if gc::collection_due() {
+            gc::collect(self.roots());
+        }
 
         match statement {
             Stmt::Block(statements) => self.execute_block(
                 statements,
                 Environment::with_enclosing(self.environment.clone()),
             ),
+            Stmt::Break { keyword: _ } => {
+                self.loop_control = Some(LoopControl::Break);
+                Ok(())
+            }
+            Stmt::ClassDecl { name, methods } => {
+                let methods = methods
+                    .iter()
+                    .map(|method| {
+                        let function = Function {
+                            declaration: method.clone(),
+                            closure: self.environment.clone(),
+                            is_initializer: method.declared_name().lexeme == "init",
+                        };
+                        (
+                            method.declared_name().lexeme.clone(),
+                            Method::Function(function),
+                        )
+                    })
+                    .collect();
+                let class = Class {
+                    name: name.lexeme.clone(),
+                    methods,
+                };
+                // The methods can only run once the class exists, so they see it in
+                // the environment they closed over
+                self.environment
+                    .borrow_mut()
+                    .define(&name.lexeme, Some(Value::Class(gc::class(class))));
+                Ok(())
+            }
+            Stmt::Continue { keyword: _ } => {
+                self.loop_control = Some(LoopControl::Continue);
+                Ok(())
+            }
             Stmt::Expression(expr) => {
                 self.evaluate(expr)?;
                 // Discard result of interpret
@@ -75,10 +201,29 @@ impl Interpreter {
                 let function = Function {
                     declaration: declaration.clone(),
                     closure: self.environment.clone(),
+                    is_initializer: false,
                 };
-                self.environment
-                    .borrow_mut()
-                    .define(&declaration.name.lexeme, Some(Value::Function(function)));
+                self.environment.borrow_mut().define(
+                    &declaration.declared_name().lexeme,
+                    Some(Value::Function(function)),
+                );
+                Ok(())
+            }
+            Stmt::Import {
+                keyword,
+                path,
+                name,
+            } => {
+                let module = self.import(keyword, path)?;
+                let mut environment = self.environment.borrow_mut();
+                match name {
+                    Some(name) => environment.define(&name.lexeme, Some(Value::Module(module))),
+                    None => {
+                        for (name, value) in module.exports().at(keyword)? {
+                            environment.define(&name, Some(value));
+                        }
+                    }
+                }
                 Ok(())
             }
             Stmt::If {
@@ -100,12 +245,73 @@ impl Interpreter {
             }
             Stmt::Return { keyword: _, value } => {
                 let value = match value {
+                    Some(Expr::Call {
+                        callee,
+                        paren,
+                        arguments,
+                    }) if self.tries == 0 => {
+                        let (callee, arguments) = self.evaluate_call(callee, arguments)?;
+                        self.tail_call = Some(TailCall {
+                            callee,
+                            arguments,
+                            paren: paren.span,
+                        });
+                        Value::Nil
+                    }
                     Some(expr) => self.evaluate(expr)?,
                     _ => Value::Nil,
                 };
                 self.return_value = Some(value);
                 Ok(())
             }
+            Stmt::Throw { keyword, value } => {
+                let value = self.evaluate(value)?;
+                Err(thrown_error(keyword, value))
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.tries += 1;
+                let mut result =
+                    self.execute_block(body, Environment::with_enclosing(self.environment.clone()));
+
+                if let (Err(error), Some(catch)) = (&result, catch) {
+                    let exception = caught_value(error, &self.error_class);
+                    let mut environment = Environment::with_enclosing(self.environment.clone());
+                    environment.define(&catch.name.lexeme, Some(exception));
+                    result = self.execute_block(&catch.body, environment);
+                }
+                self.tries -= 1;
+
+                if let Some(finally) = finally {
+                    // Put aside whatever is unwinding through here so the finally block
+                    // runs, then carry on unwinding after it unless it jumped itself
+                    let return_value = self.return_value.take();
+                    let loop_control = self.loop_control.take();
+
+                    let base = self.temporaries.len();
+                    self.temporaries.extend(return_value.clone());
+                    if let Err(Error::Thrown { value, .. }) = &result {
+                        self.temporaries.push(value.as_ref().clone());
+                    }
+                    let finally_result = self.execute_block(
+                        finally,
+                        Environment::with_enclosing(self.environment.clone()),
+                    );
+                    self.temporaries.truncate(base);
+                    finally_result?;
+
+                    if self.return_value.is_some() || self.loop_control.is_some() {
+                        return Ok(());
+                    }
+                    self.return_value = return_value;
+                    self.loop_control = loop_control;
+                }
+
+                result
+            }
             Stmt::VarDecl { name, initializer } => {
                 let value = if let Some(i) = initializer {
                     Some(self.evaluate(i)?)
@@ -115,9 +321,22 @@ impl Interpreter {
                 self.environment.borrow_mut().define(&name.lexeme, value);
                 Ok(())
             }
-            Stmt::While { condition, body } => {
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
                 while self.evaluate(condition)?.is_truthy() {
                     self.execute(body)?;
+                    if let Some(LoopControl::Break) = self.loop_control.take() {
+                        break;
+                    }
+                    if self.return_value.is_some() {
+                        break;
+                    }
+                    if let Some(increment) = increment {
+                        self.evaluate(increment)?;
+                    }
                 }
                 Ok(())
             }
@@ -125,130 +344,101 @@ impl Interpreter {
     }
 
     pub fn execute_block(&mut self, statements: &[Stmt], environment: Environment) -> Result<()> {
-        let prev = self.environment.clone();
-        let execute_statements = || -> Result<()> {
-            self.environment = Rc::new(RefCell::new(environment));
-
-            for statement in statements {
-                self.execute(statement)?;
-            }
-            Ok(())
-        };
-        let result = execute_statements();
-        self.environment = prev;
+        let environment = gc::environment(environment);
+        let prev = std::mem::replace(&mut self.environment, environment);
+        self.scopes.push(prev);
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement));
+        self.environment = self.scopes.pop().unwrap();
 
         result
     }
 
     pub fn evaluate(&mut self, expression: &Expr) -> Result<Value> {
         match expression {
-            Expr::Assign { name, value } => {
+            Expr::Assign { id, name, value } => {
                 let value = self.evaluate(value)?;
-                Ok(if let Some(distance) = self.locals.get(expression) {
+                if let Some(slot) = self.locals.get(id) {
+                    self.environment.borrow_mut().assign_at(
+                        slot.depth,
+                        slot.index,
+                        &name.lexeme,
+                        value,
+                    )
+                } else {
                     self.environment
                         .borrow_mut()
-                        .assign_at(*distance, &name.lexeme, value)?
-                } else {
-                    self.environment.borrow_mut().assign(&name.lexeme, value)?
-                })
+                        .assign_global(&name.lexeme, value)
+                }
+                .at(name)
             }
             Expr::Binary {
                 left,
                 operator,
                 right,
             } => {
-                let left = self.evaluate(left)?;
-                let right = self.evaluate(right)?;
+                let [left, right] = self.evaluate_each([left, right])?;
 
-                match operator {
-                    TokenType::Minus => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Number(left - right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::Slash => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Number(left / right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::Star => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Number(left * right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::Plus => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Number(left + right))
-                        }
-                        (Value::String(left), Value::String(right)) => {
-                            Ok(Value::String(format!("{}{}", left, right)))
-                        }
-                        _ => Err(anyhow!("Operands must be two numbers or two strings.")),
-                    },
-                    TokenType::Greater => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Boolean(left > right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::GreaterEqual => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Boolean(left >= right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::Less => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Boolean(left < right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::LessEqual => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => {
-                            Ok(Value::Boolean(left <= right))
-                        }
-                        _ => Err(error_number()),
-                    },
-                    TokenType::BangEqual => Ok(Value::Boolean(left != right)),
-                    TokenType::EqualEqual => Ok(Value::Boolean(left == right)),
-                    _ => unreachable!(),
-                }
+                ops::binary(&operator.token_type, left, right).at(operator)
             }
-            Expr::Call { callee, arguments } => {
-                let callee = self.evaluate(callee)?;
-                let mut result = vec![];
-                for argument in arguments {
-                    result.push(self.evaluate(argument)?);
-                }
-
-                let arity = match &callee {
-                    Value::NativeFunction(f) => f.get_arity(),
-                    Value::Function(f) => f.get_arity(),
-                    _ => return Err(anyhow!("Can only call functions and classes")),
-                };
-
-                let num_args: u8 = arguments.len().try_into().unwrap();
-                if num_args != arity {
-                    return Err(anyhow!(
-                        "Expected {} arguments but got {}",
-                        arity,
-                        arguments.len()
-                    ));
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let (callee, arguments) = self.evaluate_call(callee, arguments)?;
+                self.call(callee, arguments, paren.span)
+            }
+            Expr::Get { object, name } => {
+                let object = self.evaluate(object)?;
+                ops::get_property(&object, &name.lexeme).at(name)
+            }
+            Expr::Grouping(g) => self.evaluate(g),
+            Expr::Interpolation(parts) => {
+                let mut string = String::new();
+                for part in parts {
+                    string.push_str(&self.evaluate(part)?.to_string());
                 }
-
-                match callee {
-                    Value::NativeFunction(f) => f.call(self, result),
-                    Value::Function(f) => f.call(self, result),
-                    _ => unreachable!(),
+                Ok(Value::String(string.into()))
+            }
+            Expr::Lambda(declaration) => Ok(Value::Function(Function {
+                declaration: declaration.clone(),
+                closure: self.environment.clone(),
+                is_initializer: false,
+            })),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                let [object, index] = self.evaluate_each([object, index])?;
+                ops::get_index(object, index).at(bracket)
+            }
+            Expr::IndexSet {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let [object, index, value] = self.evaluate_each([object, index, value])?;
+                ops::set_index(object, index, value).at(bracket)
+            }
+            Expr::List(elements) => Ok(Value::List(gc::list(self.evaluate_all(elements)?))),
+            Expr::Map { brace, entries } => {
+                let values =
+                    self.evaluate_all(entries.iter().flat_map(|(key, value)| [key, value]))?;
+                let mut map = HashMap::new();
+                let mut values = values.into_iter();
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    map.insert(MapKey::try_from(key).at(brace)?, value);
                 }
+                Ok(Value::Map(gc::map(map)))
             }
-            Expr::Grouping(g) => self.evaluate(g),
             Expr::Literal(literal) => Ok(match literal {
+                Literal::Integer(n) => Value::Integer(*n),
                 Literal::Number(Number(n)) => Value::Number(*n),
-                Literal::String(s) => Value::String(s.to_string()),
+                Literal::String(s) => Value::String(s.clone()),
                 Literal::True => Value::Boolean(true),
                 Literal::False => Value::Boolean(false),
                 Literal::Nil => Value::Nil,
@@ -260,48 +450,235 @@ impl Interpreter {
             } => {
                 let left = self.evaluate(left)?;
 
-                match operator {
+                match operator.token_type {
                     TokenType::Or => {
                         if left.is_truthy() {
                             return Ok(left);
-                        } else {
                         }
                     }
                     TokenType::And => {
                         if !left.is_truthy() {
                             return Ok(left);
-                        } else {
                         }
                     }
                     _ => unreachable!(),
                 }
                 self.evaluate(right)
             }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                let [object, value] = self.evaluate_each([object, value])?;
+                ops::set_property(&object, &name.lexeme, value).at(name)
+            }
+            Expr::This { id, keyword } => self.lookup_variable(&keyword.lexeme, *id).at(keyword),
             Expr::Unary { operator, right } => {
                 let right = self.evaluate(right)?;
-                match operator {
-                    TokenType::Minus => match right {
-                        Value::Number(n) => Ok(Value::Number(-n)),
-                        _ => Err(error_number()),
-                    },
+                match operator.token_type {
+                    TokenType::Minus => ops::negate(right),
                     TokenType::Bang => Ok(Value::Boolean(!right.is_truthy())),
                     _ => unreachable!(),
                 }
+                .at(operator)
+            }
+            Expr::Variable { id, name } => self.lookup_variable(&name.lexeme, *id).at(name),
+        }
+    }
+
+    fn evaluate_call(&mut self, callee: &Expr, arguments: &[Expr]) -> Result<(Value, Vec<Value>)> {
+        let mut values = self.evaluate_all(iter::once(callee).chain(arguments))?;
+        let callee = values.remove(0);
+        Ok((callee, values))
+    }
+
+    /// Evaluates expressions in order, keeping the values as temporaries until
+    /// they've all been evaluated
+    fn evaluate_all<'e>(
+        &mut self,
+        expressions: impl IntoIterator<Item = &'e Expr>,
+    ) -> Result<Vec<Value>> {
+        let base = self.temporaries.len();
+        for expression in expressions {
+            match self.evaluate(expression) {
+                Ok(value) => self.temporaries.push(value),
+                Err(error) => {
+                    self.temporaries.truncate(base);
+                    return Err(error);
+                }
             }
-            Expr::Variable { name } => Ok(self.lookup_variable(&name.lexeme, expression)?),
         }
+        Ok(self.temporaries.split_off(base))
     }
 
-    pub fn resolve(&mut self, expression: &Expr, depth: u32) {
-        self.locals.insert(expression.clone(), depth);
+    fn evaluate_each<const N: usize>(&mut self, expressions: [&Expr; N]) -> Result<[Value; N]> {
+        let values = self.evaluate_all(expressions)?;
+        Ok(values.try_into().expect("One value per expression"))
     }
 
-    fn lookup_variable(&self, name: &str, expression: &Expr) -> Result<Value> {
-        if let Some(distance) = self.locals.get(expression) {
-            self.environment.borrow().get_at(*distance, name)
+    fn call(&mut self, mut callee: Value, mut arguments: Vec<Value>, paren: Span) -> Result<Value> {
+        let function = self.check_call(&callee, arguments.len(), paren)?;
+        // Each call recurses on the native stack, which mustn't overflow
+        if self.call_stack.len() >= self.max_depth {
+            return Err(stack_overflow_error(paren, self.max_depth));
+        }
+        if stack_address() < self.stack_limit {
+            return Err(stack_overflow_error(paren, self.call_stack.len()));
+        }
+        self.call_stack.push(CallFrame {
+            function,
+            call_site: paren,
+        });
+        let tries = std::mem::take(&mut self.tries);
+
+        let mut result;
+        loop {
+            result = match callee {
+                Value::NativeFunction(f) => f.call(self, arguments).at(paren),
+                Value::Function(f) => f.call(self, arguments),
+                Value::Class(c) => c.call(self, arguments),
+                _ => unreachable!(),
+            };
+
+            // A tail call replaces this one rather than nesting inside it, so returns
+            // to the same call site
+            let Some(tail_call) = self.tail_call.take() else {
+                break;
+            };
+            match self.check_call(
+                &tail_call.callee,
+                tail_call.arguments.len(),
+                tail_call.paren,
+            ) {
+                Ok(function) => {
+                    self.call_stack.last_mut().unwrap().function = function;
+                    callee = tail_call.callee;
+                    arguments = tail_call.arguments;
+                }
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+
+        // Record the stack as it was where the error happened, which is when it
+        // first unwinds through here
+        if let Err(error) = &mut result {
+            let backtrace = &mut error.diagnostic_mut().backtrace;
+            if backtrace.is_empty() {
+                *backtrace = self.call_stack.iter().rev().cloned().collect();
+            }
+        }
+        self.tries = tries;
+        self.call_stack.pop();
+        result
+    }
+
+    /// Checks that `callee` can be called with `count` arguments, returning
+    /// the name it's called by
+    fn check_call(&self, callee: &Value, count: usize, paren: Span) -> Result<String> {
+        let (function, arity) = match callee {
+            Value::NativeFunction(f) => (f.name.clone(), f.arity),
+            Value::Function(f) => (f.name().to_owned(), f.get_arity()),
+            Value::Class(c) => (c.name.clone(), c.arity()),
+            _ => {
+                return Err(runtime_error(
+                    paren,
+                    ErrorCode::NotCallable,
+                    "Can only call functions and classes",
+                ))
+            }
+        };
+
+        if count != arity as usize {
+            return Err(runtime_error(
+                paren,
+                ErrorCode::ArityMismatch,
+                format!("Expected {} arguments but got {}", arity, count),
+            ));
+        }
+        Ok(function)
+    }
+
+    /// Adds the resolved variables of code which is about to be interpreted
+    pub fn resolve(&mut self, locals: Locals) {
+        self.locals.extend(locals);
+    }
+
+    fn lookup_variable(&self, name: &str, id: ExprId) -> Result<Value, Fault> {
+        if let Some(slot) = self.locals.get(&id) {
+            self.environment
+                .borrow()
+                .get_at(slot.depth, slot.index, name)
         } else {
-            self.globals.borrow().get(name)
+            // Look in the globals of the module the code was declared in, which are
+            // at the root of its environment
+            self.environment.borrow().get_global(name)
+        }
+    }
+
+    /// Executes the module at `path` if it hasn't been already
+    fn import(&mut self, keyword: &Token, path: &str) -> Result<Rc<Module>> {
+        let mut pending = match self.loader.start(keyword.span, path)? {
+            Import::Done(module) => return Ok(module),
+            Import::Pending(pending) => pending,
+        };
+
+        // Run the module with its own globals, then restore the importer's
+        let globals = gc::environment(native::new_globals());
+        let prev_globals = std::mem::replace(&mut self.globals, globals.clone());
+        let prev_environment = std::mem::replace(&mut self.environment, globals.clone());
+        self.scopes.extend([prev_globals, prev_environment]);
+
+        self.resolve(std::mem::take(&mut pending.locals));
+        let result = self.interpret(&pending.statements);
+
+        self.environment = self.scopes.pop().unwrap();
+        self.globals = self.scopes.pop().unwrap();
+        self.loader.finish(pending, globals, result)
+    }
+
+    /// Everything the collector must keep, which is whatever the interpreter
+    /// can still reach
+    fn roots(&self) -> Vec<gc::Address> {
+        let mut roots = vec![gc::address(&self.globals), gc::address(&self.environment)];
+        roots.extend(self.scopes.iter().map(gc::address));
+        roots.extend(
+            self.loader
+                .modules()
+                .map(|module| gc::address(module.globals())),
+        );
+        let tail_call = self
+            .tail_call
+            .iter()
+            .flat_map(|tail_call| iter::once(&tail_call.callee).chain(&tail_call.arguments));
+        for value in self
+            .temporaries
+            .iter()
+            .chain(&self.return_value)
+            .chain(tail_call)
+        {
+            gc::trace_value(value, &mut roots);
         }
+        roots
+    }
+}
+
+/// Roughly where the top of the native stack is, which grows down
+fn stack_address() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+impl NativeContext for Interpreter {
+    fn call_stack(&self) -> Vec<CallFrame> {
+        self.call_stack.clone()
+    }
+
+    fn collect_garbage(&mut self) -> gc::Stats {
+        gc::collect(self.roots())
     }
 }
 
@@ -310,7 +687,3 @@ impl Default for Interpreter {
         Self::new()
     }
 }
-
-fn error_number() -> anyhow::Error {
-    anyhow!("Operand must be a number.")
-}
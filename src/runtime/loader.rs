@@ -0,0 +1,165 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use super::{environment::Environment, module::Module};
+use crate::{
+    ast::stmt::Stmt,
+    error::{runtime_error, Error, ErrorCode, Result, Span},
+    optimizer,
+    parser::Parser,
+    resolver::{Locals, Resolver},
+    scanner,
+    source::{SourceId, SourceMap},
+};
+
+/// Finds, parses and caches the modules run by a backend, and keeps the source
+/// code of everything which has been run so errors in it can be reported
+#[derive(Default)]
+pub struct Loader {
+    sources: SourceMap,
+    // Every module which has finished executing, keyed by canonical path
+    modules: HashMap<PathBuf, Rc<Module>>,
+    // Files currently being executed, innermost last. Used to resolve relative
+    // imports and detect cycles.
+    import_stack: Vec<PathBuf>,
+}
+
+pub enum Import {
+    /// The module has been run already
+    Done(Rc<Module>),
+    /// The module needs running, after which it must be passed to
+    /// Loader::finish
+    Pending(PendingModule),
+}
+
+/// A module which has been parsed and resolved but not run yet
+pub struct PendingModule {
+    pub path: PathBuf,
+    pub statements: Vec<Stmt>,
+    pub locals: Locals,
+}
+
+impl Loader {
+    /// Sets the file being run, which relative imports are resolved against
+    pub fn set_script_path(&mut self, path: &Path) -> io::Result<()> {
+        self.import_stack = vec![fs::canonicalize(path)?];
+        Ok(())
+    }
+
+    pub fn add_source(&mut self, name: impl Into<String>, text: impl Into<String>) -> SourceId {
+        self.sources.add(name, text)
+    }
+
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    /// Every module which has finished executing
+    pub fn modules(&self) -> impl Iterator<Item = &Rc<Module>> {
+        self.modules.values()
+    }
+
+    /// Loads the module at `path` unless it has been already. Failures to find
+    /// the module are reported `at` the import.
+    pub fn start(&mut self, at: Span, path: &str) -> Result<Import> {
+        let import_failed = |reason: String| {
+            runtime_error(
+                at,
+                ErrorCode::ImportFailed,
+                format!("Can't import '{}': {}", path, reason),
+            )
+        };
+
+        let base = match self.import_stack.last() {
+            Some(importer) => importer.parent().unwrap().to_path_buf(),
+            None => env::current_dir().map_err(|e| import_failed(e.to_string()))?,
+        };
+        let path = fs::canonicalize(base.join(path)).map_err(|e| import_failed(e.to_string()))?;
+
+        if let Some(module) = self.modules.get(&path) {
+            return Ok(Import::Done(module.clone()));
+        }
+
+        if self.import_stack.contains(&path) {
+            let cycle: Vec<String> = self
+                .import_stack
+                .iter()
+                .skip_while(|p| **p != path)
+                .chain([&path])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(runtime_error(
+                at,
+                ErrorCode::ImportCycle,
+                format!("Import cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+
+        let source = fs::read_to_string(&path).map_err(|e| import_failed(e.to_string()))?;
+        let id = self.sources.add(display_path(&path), source);
+        let text = &self.sources.get(id).unwrap().text;
+
+        let mut pending = PendingModule {
+            path,
+            statements: vec![],
+            locals: Locals::new(),
+        };
+        let tokens = scanner::scan_tokens(text, id).map_err(|e| pending.error(e))?;
+        let statements = Parser::new(tokens)
+            .parse()
+            .map_err(|mut errors| pending.error(errors.swap_remove(0)))?;
+        pending.locals = Resolver::new()
+            .resolve(&statements)
+            .map_err(|mut errors| pending.error(errors.swap_remove(0)))?;
+        pending.statements = optimizer::optimize(statements);
+
+        self.import_stack.push(pending.path.clone());
+        Ok(Import::Pending(pending))
+    }
+
+    /// Records the outcome of running a module, which used `globals` as its
+    /// global environment
+    pub fn finish(
+        &mut self,
+        pending: PendingModule,
+        globals: Rc<RefCell<Environment>>,
+        result: Result<()>,
+    ) -> Result<Rc<Module>> {
+        self.import_stack.pop();
+        result?;
+
+        let name = pending
+            .path
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let module = Rc::new(Module::new(name, globals, &pending.statements));
+        self.modules.insert(pending.path, module.clone());
+        Ok(module)
+    }
+}
+
+impl PendingModule {
+    /// Errors in a module are reported in its own source, the first being
+    /// enough to explain the import failing
+    pub fn error(&self, error: Error) -> Error {
+        error.with_note(format!("while importing '{}'", display_path(&self.path)))
+    }
+}
+
+/// Shows `path` relative to the working directory where possible, as it's
+/// shorter
+fn display_path(path: &Path) -> String {
+    env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
@@ -0,0 +1,157 @@
+use std::{cmp::Ordering, collections::HashMap, fmt};
+
+use super::{
+    string::Str,
+    value::{as_integer, Value},
+};
+use crate::error::{ErrorCode, Fault};
+
+/// A value which can be used as a map key.
+///
+/// Only nil, booleans, numbers and strings can be keys. Two keys are the same
+/// exactly when the values are `==` in Lox, which means numbers need care:
+/// whole floats, including `-0`, are normalised to the integer they equal, and
+/// NaN is rejected because it is never equal to itself and so could never be
+/// looked up again.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    /// The bits of a float with a fractional part, or too large to be an integer
+    Number(u64),
+    String(Str),
+}
+
+pub type Map = HashMap<MapKey, Value>;
+
+impl TryFrom<Value> for MapKey {
+    type Error = Fault;
+
+    fn try_from(value: Value) -> Result<Self, Fault> {
+        match value {
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Boolean(b) => Ok(MapKey::Boolean(b)),
+            Value::Integer(n) => Ok(MapKey::Integer(n)),
+            Value::Number(n) if n.is_nan() => {
+                Err(Fault::new(ErrorCode::InvalidMapKey, "Map key can't be NaN"))
+            }
+            Value::Number(n) => Ok(match as_integer(n) {
+                Some(n) => MapKey::Integer(n),
+                None => MapKey::Number(n.to_bits()),
+            }),
+            Value::String(s) => Ok(MapKey::String(s)),
+            _ => Err(Fault::new(
+                ErrorCode::InvalidMapKey,
+                "Map keys must be numbers, strings, booleans or nil",
+            )),
+        }
+    }
+}
+
+impl From<MapKey> for Value {
+    fn from(key: MapKey) -> Self {
+        match key {
+            MapKey::Nil => Value::Nil,
+            MapKey::Boolean(b) => Value::Boolean(b),
+            MapKey::Integer(n) => Value::Integer(n),
+            MapKey::Number(bits) => Value::Number(f64::from_bits(bits)),
+            MapKey::String(s) => Value::String(s),
+        }
+    }
+}
+
+// Keys are ordered so that iteration over a map is deterministic: nil, then
+// booleans, then numbers, then strings
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MapKey::Boolean(l), MapKey::Boolean(r)) => l.cmp(r),
+            (MapKey::Integer(l), MapKey::Integer(r)) => l.cmp(r),
+            (MapKey::Number(l), MapKey::Number(r)) => {
+                f64::from_bits(*l).total_cmp(&f64::from_bits(*r))
+            }
+            // Integers and floats are ordered together by value. They can only be
+            // equal as floats if the integer was rounded, so it goes first.
+            (MapKey::Integer(l), MapKey::Number(r)) => (*l as f64)
+                .total_cmp(&f64::from_bits(*r))
+                .then(Ordering::Less),
+            (MapKey::Number(_), MapKey::Integer(_)) => other.cmp(self).reverse(),
+            (MapKey::String(l), MapKey::String(r)) => l.cmp(r),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl MapKey {
+    fn rank(&self) -> u8 {
+        match self {
+            MapKey::Nil => 0,
+            MapKey::Boolean(_) => 1,
+            MapKey::Integer(_) | MapKey::Number(_) => 2,
+            MapKey::String(_) => 3,
+        }
+    }
+}
+
+// Strings are quoted so that a key like "1" can't be mistaken for the number
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::String(s) => fmt::Debug::fmt(s, f),
+            _ => fmt::Display::fmt(&Value::from(self.clone()), f),
+        }
+    }
+}
+
+/// The keys of `map` in iteration order
+pub fn sorted_keys(map: &Map) -> Vec<&MapKey> {
+    let mut keys: Vec<&MapKey> = map.keys().collect();
+    keys.sort();
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MapKey;
+    use crate::runtime::value::Value;
+
+    #[test]
+    fn equal_numbers_are_equal_keys() {
+        let zero = MapKey::try_from(Value::Integer(0)).unwrap();
+        let negative_zero = MapKey::try_from(Value::Number(-0.0)).unwrap();
+        assert!(zero == negative_zero);
+        let two = MapKey::try_from(Value::Number(2.0)).unwrap();
+        assert!(two == MapKey::Integer(2));
+        assert!(MapKey::try_from(Value::Number(2.5)).unwrap() != two);
+    }
+
+    #[test]
+    fn nan_is_not_a_key() {
+        assert!(MapKey::try_from(Value::Number(f64::NAN)).is_err());
+    }
+
+    #[test]
+    fn keys_are_ordered_by_type_then_value() {
+        let mut keys: Vec<MapKey> = [
+            Value::String("a".into()),
+            Value::Integer(2),
+            Value::Boolean(true),
+            Value::Number(-1.5),
+            Value::Nil,
+            Value::Number(1e300),
+        ]
+        .into_iter()
+        .map(|value| MapKey::try_from(value).unwrap())
+        .collect();
+        keys.sort();
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        assert_eq!(keys, ["nil", "true", "-1.5", "2", "1e300", "\"a\""]);
+    }
+}
@@ -1,4 +1,12 @@
+pub mod class;
 pub mod environment;
 pub mod function;
+pub mod gc;
 pub mod interpreter;
+pub mod loader;
+pub mod map;
+pub mod module;
+pub mod native;
+pub mod ops;
+pub mod string;
 pub mod value;
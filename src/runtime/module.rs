@@ -0,0 +1,72 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use super::{environment::Environment, value::Value};
+use crate::{
+    ast::stmt::Stmt,
+    error::{ErrorCode, Fault},
+};
+
+/// A Lox file which has been imported and executed
+pub struct Module {
+    pub name: String,
+    globals: Rc<RefCell<Environment>>,
+    exports: Vec<String>,
+}
+
+impl Module {
+    pub fn new(name: String, globals: Rc<RefCell<Environment>>, statements: &[Stmt]) -> Self {
+        Self {
+            name,
+            globals,
+            exports: exported_names(statements),
+        }
+    }
+
+    pub fn globals(&self) -> &Rc<RefCell<Environment>> {
+        &self.globals
+    }
+
+    pub fn get(&self, name: &str) -> Result<Value, Fault> {
+        if !self.exports.iter().any(|export| export == name) {
+            return Err(Fault::new(
+                ErrorCode::UndefinedProperty,
+                format!("Module '{}' has no export '{}'", self.name, name),
+            ));
+        }
+        self.globals.borrow().get(name)
+    }
+
+    /// Every exported name along with its current value
+    pub fn exports(&self) -> Result<Vec<(String, Value)>, Fault> {
+        let globals = self.globals.borrow();
+        self.exports
+            .iter()
+            .map(|name| Ok((name.clone(), globals.get(name)?)))
+            .collect()
+    }
+}
+
+/// A module exports every name it declares at the top level, including modules
+/// it binds with `import name from`
+fn exported_names(statements: &[Stmt]) -> Vec<String> {
+    let mut names = vec![];
+    for statement in statements {
+        match statement {
+            Stmt::ClassDecl { name, .. } | Stmt::VarDecl { name, .. } => {
+                names.push(name.lexeme.clone())
+            }
+            Stmt::FunctionDecl(decl) => names.push(decl.declared_name().lexeme.clone()),
+            Stmt::Import {
+                name: Some(name), ..
+            } => names.push(name.lexeme.clone()),
+            _ => {}
+        }
+    }
+    names
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format!("<module {}>", self.name))
+    }
+}
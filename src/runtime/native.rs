@@ -0,0 +1,187 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    environment::Environment,
+    function::{NativeContext, NativeFunction},
+    gc,
+    map::{self, Map, MapKey},
+    string::Str,
+    value::Value,
+};
+use crate::error::{ErrorCode, Fault};
+
+/// A global environment containing only the native functions
+pub fn new_globals() -> Environment {
+    let mut globals = Environment::new();
+    for native in natives() {
+        let name = native.name.clone();
+        globals.define(&name, Some(Value::NativeFunction(native)));
+    }
+    globals
+}
+
+/// All native functions, which are defined in the global scope
+fn natives() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction {
+            arity: 0,
+            func: clock,
+            name: "clock".to_string(),
+        },
+        NativeFunction {
+            arity: 1,
+            func: len,
+            name: "len".to_string(),
+        },
+        NativeFunction {
+            arity: 2,
+            func: push,
+            name: "push".to_string(),
+        },
+        NativeFunction {
+            arity: 1,
+            func: pop,
+            name: "pop".to_string(),
+        },
+        NativeFunction {
+            arity: 1,
+            func: keys,
+            name: "keys".to_string(),
+        },
+        NativeFunction {
+            arity: 2,
+            func: has,
+            name: "has".to_string(),
+        },
+        NativeFunction {
+            arity: 2,
+            func: remove,
+            name: "remove".to_string(),
+        },
+        NativeFunction {
+            arity: 0,
+            func: backtrace,
+            name: "backtrace".to_string(),
+        },
+        NativeFunction {
+            arity: 0,
+            func: collect_garbage,
+            name: "gc".to_string(),
+        },
+    ]
+}
+
+fn clock(_: &mut dyn NativeContext, _: Vec<Value>) -> Result<Value, Fault> {
+    let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Ok(Value::Number(since_the_epoch.as_secs_f64()))
+}
+
+fn len(_: &mut dyn NativeContext, arguments: Vec<Value>) -> Result<Value, Fault> {
+    match &arguments[0] {
+        Value::List(list) => Ok(Value::Integer(list.borrow().len() as i64)),
+        Value::Map(map) => Ok(Value::Integer(map.borrow().len() as i64)),
+        Value::String(s) => Ok(Value::Integer(s.chars().count() as i64)),
+        _ => Err(Fault::new(
+            ErrorCode::TypeMismatch,
+            "Can only get the length of lists, maps and strings",
+        )),
+    }
+}
+
+fn push(_: &mut dyn NativeContext, mut arguments: Vec<Value>) -> Result<Value, Fault> {
+    let value = arguments.pop().unwrap();
+    match &arguments[0] {
+        Value::List(list) => {
+            list.borrow_mut().push(value);
+            Ok(Value::Nil)
+        }
+        _ => Err(Fault::new(
+            ErrorCode::TypeMismatch,
+            "Can only push to lists",
+        )),
+    }
+}
+
+fn pop(_: &mut dyn NativeContext, arguments: Vec<Value>) -> Result<Value, Fault> {
+    match &arguments[0] {
+        Value::List(list) => list
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| Fault::new(ErrorCode::EmptyList, "Can't pop from an empty list")),
+        _ => Err(Fault::new(
+            ErrorCode::TypeMismatch,
+            "Can only pop from lists",
+        )),
+    }
+}
+
+fn keys(_: &mut dyn NativeContext, arguments: Vec<Value>) -> Result<Value, Fault> {
+    match &arguments[0] {
+        Value::Map(entries) => {
+            let keys = map::sorted_keys(&entries.borrow())
+                .into_iter()
+                .map(|key| Value::from(key.clone()))
+                .collect();
+            Ok(Value::List(gc::list(keys)))
+        }
+        _ => Err(Fault::new(
+            ErrorCode::TypeMismatch,
+            "Can only get the keys of maps",
+        )),
+    }
+}
+
+fn has(_: &mut dyn NativeContext, mut arguments: Vec<Value>) -> Result<Value, Fault> {
+    let key = MapKey::try_from(arguments.pop().unwrap())?;
+    match &arguments[0] {
+        Value::Map(map) => Ok(Value::Boolean(map.borrow().contains_key(&key))),
+        _ => Err(Fault::new(
+            ErrorCode::TypeMismatch,
+            "Can only check for keys in maps",
+        )),
+    }
+}
+
+/// Removes `key` from the map, returning its value or nil if it was missing
+fn remove(_: &mut dyn NativeContext, mut arguments: Vec<Value>) -> Result<Value, Fault> {
+    let key = MapKey::try_from(arguments.pop().unwrap())?;
+    match &arguments[0] {
+        Value::Map(map) => Ok(map.borrow_mut().remove(&key).unwrap_or(Value::Nil)),
+        _ => Err(Fault::new(
+            ErrorCode::TypeMismatch,
+            "Can only remove keys from maps",
+        )),
+    }
+}
+
+/// Names of the functions currently being called, innermost first, not
+/// including backtrace itself
+fn backtrace(context: &mut dyn NativeContext, _: Vec<Value>) -> Result<Value, Fault> {
+    let names = context
+        .call_stack()
+        .iter()
+        .rev()
+        .skip(1)
+        .map(|frame| Value::String(frame.function.as_str().into()))
+        .collect();
+    Ok(Value::List(gc::list(names)))
+}
+
+/// Runs the garbage collector, returning a map of what it has done so far
+fn collect_garbage(context: &mut dyn NativeContext, _: Vec<Value>) -> Result<Value, Fault> {
+    let stats = context.collect_garbage();
+    let entries: Map = [
+        ("collections", stats.collections),
+        ("freed", stats.freed),
+        ("tracked", stats.tracked),
+    ]
+    .into_iter()
+    .map(|(name, count)| {
+        (
+            MapKey::String(Str::intern(name)),
+            Value::Integer(count as i64),
+        )
+    })
+    .collect();
+    Ok(Value::Map(gc::map(entries)))
+}
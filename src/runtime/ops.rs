@@ -0,0 +1,264 @@
+//! Semantics of the operators, shared by every backend
+
+use std::cmp::Ordering;
+
+use super::{class::Instance, map::MapKey, value::Value};
+use crate::{
+    error::{ErrorCode, Fault},
+    scanner::TokenType,
+};
+
+/// Applies the binary operator `operator`, which must not be a logical one
+pub fn binary(operator: &TokenType, left: Value, right: Value) -> Result<Value, Fault> {
+    match operator {
+        TokenType::Minus => subtract(left, right),
+        TokenType::Slash => divide(left, right),
+        TokenType::Star => multiply(left, right),
+        TokenType::Percent => modulo(left, right),
+        TokenType::Plus => add(left, right),
+        TokenType::Greater => greater(left, right),
+        TokenType::GreaterEqual => greater_equal(left, right),
+        TokenType::Less => less(left, right),
+        TokenType::LessEqual => less_equal(left, right),
+        TokenType::BangEqual => Ok(Value::Boolean(left != right)),
+        TokenType::EqualEqual => Ok(Value::Boolean(left == right)),
+        _ => unreachable!(),
+    }
+}
+
+pub fn add(left: Value, right: Value) -> Result<Value, Fault> {
+    match (left, right) {
+        (Value::String(left), Value::String(right)) => {
+            Ok(Value::String(format!("{}{}", left, right).into()))
+        }
+        (left, right) => match operands(left, right) {
+            Ok(Operands::Integers(left, right)) => integer(left.checked_add(right)),
+            Ok(Operands::Floats(left, right)) => Ok(Value::Number(left + right)),
+            Err(_) => Err(Fault::new(
+                ErrorCode::TypeMismatch,
+                "Operands must be two numbers or two strings.",
+            )),
+        },
+    }
+}
+
+pub fn subtract(left: Value, right: Value) -> Result<Value, Fault> {
+    match operands(left, right)? {
+        Operands::Integers(left, right) => integer(left.checked_sub(right)),
+        Operands::Floats(left, right) => Ok(Value::Number(left - right)),
+    }
+}
+
+pub fn multiply(left: Value, right: Value) -> Result<Value, Fault> {
+    match operands(left, right)? {
+        Operands::Integers(left, right) => integer(left.checked_mul(right)),
+        Operands::Floats(left, right) => Ok(Value::Number(left * right)),
+    }
+}
+
+/// Divides, rounding towards zero if both operands are integers
+pub fn divide(left: Value, right: Value) -> Result<Value, Fault> {
+    match operands(left, right)? {
+        Operands::Integers(_, 0) => Err(division_by_zero()),
+        Operands::Integers(left, right) => integer(left.checked_div(right)),
+        Operands::Floats(left, right) => Ok(Value::Number(left / right)),
+    }
+}
+
+/// The remainder of dividing, which has the same sign as `left`
+pub fn modulo(left: Value, right: Value) -> Result<Value, Fault> {
+    match operands(left, right)? {
+        Operands::Integers(_, 0) => Err(division_by_zero()),
+        // Only i64::MIN % -1 overflows, yet its remainder is 0 all the same
+        Operands::Integers(left, right) => Ok(Value::Integer(left.wrapping_rem(right))),
+        Operands::Floats(left, right) => Ok(Value::Number(left % right)),
+    }
+}
+
+pub fn greater(left: Value, right: Value) -> Result<Value, Fault> {
+    let ordering = compare(left, right)?;
+    Ok(Value::Boolean(matches!(ordering, Some(Ordering::Greater))))
+}
+
+pub fn greater_equal(left: Value, right: Value) -> Result<Value, Fault> {
+    let ordering = compare(left, right)?;
+    Ok(Value::Boolean(matches!(
+        ordering,
+        Some(Ordering::Greater | Ordering::Equal)
+    )))
+}
+
+pub fn less(left: Value, right: Value) -> Result<Value, Fault> {
+    let ordering = compare(left, right)?;
+    Ok(Value::Boolean(matches!(ordering, Some(Ordering::Less))))
+}
+
+pub fn less_equal(left: Value, right: Value) -> Result<Value, Fault> {
+    let ordering = compare(left, right)?;
+    Ok(Value::Boolean(matches!(
+        ordering,
+        Some(Ordering::Less | Ordering::Equal)
+    )))
+}
+
+pub fn negate(value: Value) -> Result<Value, Fault> {
+    match value {
+        Value::Integer(n) => integer(n.checked_neg()),
+        Value::Number(n) => Ok(Value::Number(-n)),
+        _ => Err(error_number()),
+    }
+}
+
+/// Operands of an arithmetic operator. They're only integers if both of them
+/// are, as an integer is promoted to a float when mixed with one.
+enum Operands {
+    Integers(i64, i64),
+    Floats(f64, f64),
+}
+
+fn operands(left: Value, right: Value) -> Result<Operands, Fault> {
+    match (left, right) {
+        (Value::Integer(left), Value::Integer(right)) => Ok(Operands::Integers(left, right)),
+        (left, right) => Ok(Operands::Floats(float(left)?, float(right)?)),
+    }
+}
+
+fn float(value: Value) -> Result<f64, Fault> {
+    match value {
+        Value::Integer(n) => Ok(n as f64),
+        Value::Number(n) => Ok(n),
+        _ => Err(error_number()),
+    }
+}
+
+/// Orders two numbers, or returns None if either is NaN
+fn compare(left: Value, right: Value) -> Result<Option<Ordering>, Fault> {
+    Ok(match (left, right) {
+        (Value::Integer(left), Value::Number(right)) => compare_mixed(left, right),
+        (Value::Number(left), Value::Integer(right)) => {
+            compare_mixed(right, left).map(Ordering::reverse)
+        }
+        (left, right) => match operands(left, right)? {
+            Operands::Integers(left, right) => Some(left.cmp(&right)),
+            Operands::Floats(left, right) => left.partial_cmp(&right),
+        },
+    })
+}
+
+/// Orders an integer and a float exactly, as `==` does, rather than rounding
+/// the integer to a float first
+fn compare_mixed(integer: i64, float: f64) -> Option<Ordering> {
+    // Every float in this range has a whole part which converts exactly
+    let limit = 2f64.powi(63);
+    if float >= limit {
+        return Some(Ordering::Less);
+    }
+    if float < -limit {
+        return Some(Ordering::Greater);
+    }
+    let fraction = 0f64.partial_cmp(&float.fract())?;
+    Some(integer.cmp(&(float.trunc() as i64)).then(fraction))
+}
+
+/// The result of integer arithmetic, which is None if it overflowed
+fn integer(result: Option<i64>) -> Result<Value, Fault> {
+    result.map(Value::Integer).ok_or_else(|| {
+        Fault::new(ErrorCode::IntegerOverflow, "Integer overflow").with_help(format!(
+            "integers must be between {} and {}, but floats like 1.0 can be larger",
+            i64::MIN,
+            i64::MAX
+        ))
+    })
+}
+
+fn division_by_zero() -> Fault {
+    Fault::new(ErrorCode::DivisionByZero, "Division by zero")
+}
+
+fn error_number() -> Fault {
+    Fault::new(ErrorCode::TypeMismatch, "Operand must be a number.")
+}
+
+pub fn get_property(object: &Value, name: &str) -> Result<Value, Fault> {
+    match object {
+        Value::Instance(instance) => Instance::get(instance, name),
+        Value::Module(module) => module.get(name),
+        _ => Err(Fault::new(
+            ErrorCode::UndefinedProperty,
+            "Only instances and modules have properties",
+        )),
+    }
+}
+
+pub fn set_property(object: &Value, name: &str, value: Value) -> Result<Value, Fault> {
+    match object {
+        Value::Instance(instance) => {
+            instance.borrow_mut().set(name, value.clone());
+            Ok(value)
+        }
+        _ => Err(Fault::new(
+            ErrorCode::UndefinedProperty,
+            "Only instances have fields",
+        )),
+    }
+}
+
+pub fn get_index(object: Value, index: Value) -> Result<Value, Fault> {
+    match object {
+        Value::List(list) => {
+            let list = list.borrow();
+            Ok(list[list_index(&index, list.len())?].clone())
+        }
+        Value::Map(map) => {
+            let key = MapKey::try_from(index)?;
+            map.borrow().get(&key).cloned().ok_or_else(|| {
+                Fault::new(ErrorCode::UndefinedKey, format!("Undefined key {}", key))
+            })
+        }
+        _ => Err(not_indexable()),
+    }
+}
+
+pub fn set_index(object: Value, index: Value, value: Value) -> Result<Value, Fault> {
+    match object {
+        Value::List(list) => {
+            let mut list = list.borrow_mut();
+            let i = list_index(&index, list.len())?;
+            list[i] = value.clone();
+            Ok(value)
+        }
+        Value::Map(map) => {
+            let key = MapKey::try_from(index)?;
+            map.borrow_mut().insert(key, value.clone());
+            Ok(value)
+        }
+        _ => Err(not_indexable()),
+    }
+}
+
+fn not_indexable() -> Fault {
+    Fault::new(
+        ErrorCode::NotIndexable,
+        "Only lists and maps can be indexed",
+    )
+}
+
+/// Checks that `index` is an integer within the bounds of a list of length
+/// `len`
+fn list_index(index: &Value, len: usize) -> Result<usize, Fault> {
+    match index {
+        Value::Integer(i) => usize::try_from(*i)
+            .ok()
+            .filter(|&i| i < len)
+            .ok_or_else(|| {
+                Fault::new(
+                    ErrorCode::IndexOutOfRange,
+                    format!("List index {} out of range for list of length {}", i, len),
+                )
+            }),
+        _ => Err(Fault::new(
+            ErrorCode::InvalidIndex,
+            "List index must be an integer",
+        )),
+    }
+}
@@ -0,0 +1,118 @@
+use std::{borrow::Borrow, cell::RefCell, collections::HashSet, fmt, hash, ops::Deref, rc::Rc};
+
+thread_local! {
+    static INTERNED: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+/// An immutable string which is cheap to copy.
+///
+/// Identifiers and string literals are interned, so there's only one copy of
+/// each and comparing them only compares pointers. Strings built at runtime
+/// aren't, but still compare equal to interned strings with the same contents.
+#[derive(Clone)]
+pub struct Str(Rc<str>);
+
+impl Str {
+    /// The one shared copy of `s`
+    pub fn intern(s: &str) -> Str {
+        INTERNED.with(|interned| {
+            let mut interned = interned.borrow_mut();
+            match interned.get(s) {
+                Some(s) => Str(s.clone()),
+                None => {
+                    let s: Rc<str> = Rc::from(s);
+                    interned.insert(s.clone());
+                    Str(s)
+                }
+            }
+        })
+    }
+}
+
+impl From<String> for Str {
+    fn from(s: String) -> Self {
+        Str(Rc::from(s))
+    }
+}
+
+impl From<&str> for Str {
+    fn from(s: &str) -> Self {
+        Str(Rc::from(s))
+    }
+}
+
+impl Deref for Str {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+// Lets maps keyed by Str be looked up with a &str
+impl Borrow<str> for Str {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Str {}
+
+// Must hash the same as a &str for Borrow
+impl hash::Hash for Str {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl PartialOrd for Str {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Str {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Str;
+
+    #[test]
+    fn interned_strings_are_shared() {
+        let a = Str::intern("name");
+        let b = Str::intern(&String::from("name"));
+        assert!(Rc::ptr_eq(&a.0, &b.0));
+        assert!(!Rc::ptr_eq(&a.0, &Str::intern("other").0));
+    }
+
+    #[test]
+    fn equality_is_by_contents() {
+        let built = Str::from(format!("{}{}", "na", "me"));
+        assert_eq!(built, Str::intern("name"));
+        assert_ne!(built, Str::intern("names"));
+    }
+}
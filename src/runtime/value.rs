@@ -1,6 +1,13 @@
-use std::fmt;
+use std::{cell::RefCell, fmt, rc::Rc};
 
-use super::function::{Function, NativeFunction};
+use super::{
+    class::{Class, Instance},
+    function::{Function, NativeFunction},
+    map::{self, Map},
+    module::Module,
+    string::Str,
+};
+use crate::vm::object::{BoundMethod, Closure};
 
 // Clone: often generated as result of expression, other times copied out of
 // environment
@@ -8,10 +15,19 @@ use super::function::{Function, NativeFunction};
 pub enum Value {
     Nil,
     Boolean(bool),
+    Integer(i64),
+    /// A float. Integers are numbers too, but are kept apart so they stay exact
     Number(f64),
-    String(String),
+    String(Str),
     Function(Function),
     NativeFunction(NativeFunction),
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<Map>>),
+    Module(Rc<Module>),
 }
 
 impl Value {
@@ -28,9 +44,18 @@ impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Boolean(l), Self::Boolean(r)) => l == r,
+            (Self::Integer(l), Self::Integer(r)) => l == r,
             (Self::Number(l), Self::Number(r)) => l == r,
+            (Self::Integer(i), Self::Number(n)) | (Self::Number(n), Self::Integer(i)) => {
+                as_integer(*n) == Some(*i)
+            }
             (Self::String(l), Self::String(r)) => l == r,
             (Self::Nil, Self::Nil) => true,
+            (Self::Class(l), Self::Class(r)) => Rc::ptr_eq(l, r),
+            (Self::Instance(l), Self::Instance(r)) => Rc::ptr_eq(l, r),
+            (Self::List(l), Self::List(r)) => Rc::ptr_eq(l, r),
+            (Self::Map(l), Self::Map(r)) => Rc::ptr_eq(l, r),
+            (Self::Module(l), Self::Module(r)) => Rc::ptr_eq(l, r),
             _ => false,
         }
     }
@@ -38,17 +63,76 @@ impl PartialEq for Value {
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &mut vec![])
+    }
+}
+
+impl Value {
+    /// Writes the value for display. `printing` holds the lists and maps being
+    /// written around it, so that one which contains itself is shown as `[...]`
+    /// or `{...}` rather than recursing forever.
+    fn write(&self, f: &mut fmt::Formatter<'_>, printing: &mut Vec<*const ()>) -> fmt::Result {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::Boolean(b) => std::fmt::Display::fmt(&b, f),
-            Value::Number(n) => std::fmt::Display::fmt(&n, f),
+            Value::Integer(n) => std::fmt::Display::fmt(&n, f),
+            // Debug formatting always has a decimal point or exponent, so floats
+            // can't be mistaken for integers
+            Value::Number(n) => std::fmt::Debug::fmt(&n, f),
             Value::String(s) => f.write_str(s),
             Value::NativeFunction(func) => std::fmt::Display::fmt(func, f),
             Value::Function(func) => std::fmt::Display::fmt(func, f),
+            Value::Closure(closure) => std::fmt::Display::fmt(closure, f),
+            Value::BoundMethod(method) => std::fmt::Display::fmt(method, f),
+            Value::Class(class) => std::fmt::Display::fmt(class, f),
+            Value::Instance(instance) => std::fmt::Display::fmt(&instance.borrow(), f),
+            Value::List(list) => {
+                let pointer = Rc::as_ptr(list) as *const ();
+                if printing.contains(&pointer) {
+                    return f.write_str("[...]");
+                }
+                printing.push(pointer);
+                f.write_str("[")?;
+                for (i, element) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    element.write(f, printing)?;
+                }
+                printing.pop();
+                f.write_str("]")
+            }
+            Value::Map(entries) => {
+                let pointer = Rc::as_ptr(entries) as *const ();
+                if printing.contains(&pointer) {
+                    return f.write_str("{...}");
+                }
+                printing.push(pointer);
+                let entries = entries.borrow();
+                f.write_str("{")?;
+                for (i, key) in map::sorted_keys(&entries).into_iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: ", key)?;
+                    entries[key].write(f, printing)?;
+                }
+                printing.pop();
+                f.write_str("}")
+            }
+            Value::Module(module) => std::fmt::Display::fmt(module, f),
         }
     }
 }
 
+/// The integer equal to `n`, if there is one
+pub fn as_integer(n: f64) -> Option<i64> {
+    // Every whole float in this range converts exactly, unlike i64::MAX which
+    // rounds up to 2^63 as a float
+    let in_range = (-(2f64.powi(63))..2f64.powi(63)).contains(&n);
+    (in_range && n.fract() == 0.0).then_some(n as i64)
+}
+
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         std::fmt::Display::fmt(&self, f)
@@ -1,23 +1,34 @@
 use std::{fmt, hash::Hash};
 
-use anyhow::{anyhow, Result};
 use phf::phf_map;
 
+use crate::{
+    error::{Diagnostic, Error, ErrorCode, Result, Span},
+    source::SourceId,
+};
+
 static KEYWORDS: phf::Map<&'static str, TokenType> = phf_map! {
     "and" => TokenType::And,
+    "break" => TokenType::Break,
+    "catch" => TokenType::Catch,
     "class" => TokenType::Class,
+    "continue" => TokenType::Continue,
     "else" => TokenType::Else,
     "false" => TokenType::False,
+    "finally" => TokenType::Finally,
     "for" => TokenType::For,
     "fun" => TokenType::Fun,
     "if" => TokenType::If,
+    "import" => TokenType::Import,
     "nil" => TokenType::Nil,
     "or" => TokenType::Or,
     "print" => TokenType::Print,
     "return" => TokenType::Return,
     "super" => TokenType::Super,
     "this" => TokenType::This,
+    "throw" => TokenType::Throw,
     "true" => TokenType::True,
+    "try" => TokenType::Try,
     "var" => TokenType::Var,
     "while" => TokenType::While,
 };
@@ -31,9 +42,11 @@ pub enum TokenType {
     RightBrace,
     LeftBracket,
     RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
+    Percent,
     Plus,
     Semicolon,
     Slash,
@@ -52,31 +65,43 @@ pub enum TokenType {
     // Literals.
     Identifier,
     String(String),
+    Integer(i64),
+    /// Part of a string up to an interpolated expression, which is scanned as
+    /// the tokens following it. The rest of the string is scanned from the
+    /// `}` closing the expression.
+    Interpolation(String),
     Number(Number),
 
     // Keywords.
     And,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
     Eof,
 }
 
-// We will get lots of equality and hashing issues with NaNs, but for us this is
-// acceptable?
+// Bit equality is sound here as literals can never be NaN. Map keys, which can
+// be computed at runtime, handle NaN themselves in runtime::map
 #[derive(Debug, Clone, Copy)]
 pub struct Number(pub f64);
 
@@ -98,8 +123,7 @@ impl Hash for Number {
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
-    pub line: usize,
-    pub col: u32,
+    pub span: Span,
 }
 
 impl fmt::Debug for Token {
@@ -107,19 +131,31 @@ impl fmt::Debug for Token {
         write!(
             f,
             "Token {{ ty: {:?}, lexeme: \"{}\", line: {:?}, col: {:?}}}",
-            self.token_type, self.lexeme, self.line, self.col
+            self.token_type, self.lexeme, self.span.line, self.span.col
         )
     }
 }
 
-pub fn scan_tokens(input: &str) -> Result<Vec<Token>> {
+/// Help for an integer literal which doesn't fit, which the parser reports too
+pub fn integer_too_large_help() -> String {
+    format!(
+        "integers can be at most {}, but a float like 1e19 can be larger",
+        i64::MAX
+    )
+}
+
+pub fn scan_tokens(input: &str, source_id: SourceId) -> Result<Vec<Token>> {
     let mut scanner = Scanner {
         source: input,
+        source_id,
         tokens: vec![],
         start: 0,
         current: 0,
         line: 1,
         col: 0,
+        start_line: 1,
+        start_col: 1,
+        interpolations: vec![],
     };
 
     scanner.scan_tokens()?;
@@ -129,35 +165,68 @@ pub fn scan_tokens(input: &str) -> Result<Vec<Token>> {
 
 struct Scanner<'a> {
     source: &'a str,
+    source_id: SourceId,
     tokens: Vec<Token>,
     start: usize,
     current: usize,
     line: usize,
+    // Column of the last character consumed, counted in characters rather
+    // than bytes
     col: u32,
+    // Position of the first character of the current token
+    start_line: usize,
+    start_col: u32,
+    // Interpolated expressions being scanned, innermost last
+    interpolations: Vec<Interpolation>,
+}
+
+/// An expression embedded in a string with `${...}`
+struct Interpolation {
+    /// Span of the `${` starting the expression
+    span: Span,
+    /// Braces opened within the expression which haven't been closed yet
+    braces: usize,
 }
 
 impl<'a> Scanner<'a> {
     fn scan_tokens(&mut self) -> Result<()> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_col = self.col + 1;
             self.scan_token()?;
         }
 
-        self.tokens.push(Token {
-            token_type: TokenType::Eof,
-            lexeme: String::new(),
-            line: self.line,
-            col: self.col,
-        });
+        if let Some(interpolation) = self.interpolations.pop() {
+            return Err(Error::Scan(Box::new(Diagnostic::new(
+                ErrorCode::UnterminatedInterpolation,
+                "Unterminated interpolation",
+                interpolation.span,
+            )))
+            .with_help("add a '}' to end the interpolated expression"));
+        }
+
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_col = self.col + 1;
+        self.add_token(TokenType::Eof);
 
         Ok(())
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.col += 1;
-
-        char::from(self.source.as_bytes()[self.current - 1])
+        if self.is_at_end() {
+            return '\0';
+        }
+        let c = self.peek();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 0;
+        } else {
+            self.col += 1;
+        }
+        c
     }
 
     fn scan_token(&mut self) -> Result<()> {
@@ -166,13 +235,31 @@ impl<'a> Scanner<'a> {
         match c {
             '(' => self.add_token(TokenType::LeftParen),
             ')' => self.add_token(TokenType::RightParen),
-            '{' => self.add_token(TokenType::LeftBrace),
-            '}' => self.add_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.braces += 1;
+                }
+                self.add_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                // This closes the interpolated expression, so the string carries on
+                Some(interpolation) if interpolation.braces == 0 => {
+                    self.interpolations.pop();
+                    self.string()?
+                }
+                Some(interpolation) => {
+                    interpolation.braces -= 1;
+                    self.add_token(TokenType::RightBrace)
+                }
+                None => self.add_token(TokenType::RightBrace),
+            },
             '[' => self.add_token(TokenType::LeftBracket),
             ']' => self.add_token(TokenType::RightBracket),
+            ':' => self.add_token(TokenType::Colon),
             ',' => self.add_token(TokenType::Comma),
             '.' => self.add_token(TokenType::Dot),
             '-' => self.add_token(TokenType::Minus),
+            '%' => self.add_token(TokenType::Percent),
             '+' => self.add_token(TokenType::Plus),
             ';' => self.add_token(TokenType::Semicolon),
             '*' => self.add_token(TokenType::Star),
@@ -217,19 +304,18 @@ impl<'a> Scanner<'a> {
                     self.add_token(TokenType::Slash)
                 }
             }
-            ' ' | '\r' | '\t' => {}
-            '\n' => {
-                self.line += 1;
-                self.col = 0
-            }
+            ' ' | '\r' | '\t' | '\n' => {}
             '"' => self.string()?,
             _ => {
                 if Scanner::is_decimal_digit(c) {
-                    self.number()
+                    self.number(c)?
                 } else if Scanner::is_alpha(c) {
                     self.identifier()
                 } else {
-                    return Err(anyhow!("scanner can't handle {}", c));
+                    return Err(self.error(
+                        ErrorCode::UnexpectedCharacter,
+                        format!("Unexpected character '{}'", c),
+                    ));
                 }
             }
         }
@@ -237,11 +323,11 @@ impl<'a> Scanner<'a> {
     }
 
     fn is_alpha(c: char) -> bool {
-        c.is_alphabetic()
+        c.is_alphabetic() || c == '_'
     }
 
     fn is_decimal_digit(c: char) -> bool {
-        c.is_digit(10)
+        c.is_ascii_digit()
     }
 
     fn is_alphanumeric(c: char) -> bool {
@@ -261,73 +347,218 @@ impl<'a> Scanner<'a> {
         };
     }
 
-    fn number(&mut self) {
-        while Scanner::is_decimal_digit(self.peek()) {
-            self.advance();
+    /// Scans a number literal starting with the digit `first`. It's a float if
+    /// it has a fractional part or an exponent, and an integer otherwise.
+    fn number(&mut self, first: char) -> Result<()> {
+        let token_type = match (first, self.peek()) {
+            ('0', 'x' | 'X') => TokenType::Integer(self.prefixed_number(16, "hex")?),
+            ('0', 'b' | 'B') => TokenType::Integer(self.prefixed_number(2, "binary")?),
+            _ => self.decimal_number()?,
+        };
+
+        // Catch things like `0b12` and `1_` rather than leaving them to be scanned
+        // as another token
+        if Scanner::is_alphanumeric(self.peek()) {
+            let c = self.advance();
+            return Err(self.error(
+                ErrorCode::InvalidNumber,
+                format!("Unexpected character '{}' in number", c),
+            ));
         }
 
+        self.add_token(token_type);
+        Ok(())
+    }
+
+    /// Scans the rest of an integer after a `0x` or `0b` prefix
+    fn prefixed_number(&mut self, radix: u32, name: &str) -> Result<i64> {
+        let prefix = self.advance();
+        let start = self.current;
+        if !self.digits(radix) {
+            return Err(self.error(
+                ErrorCode::InvalidNumber,
+                format!("Expect {} digits after '0{}'", name, prefix),
+            ));
+        }
+
+        let digits: String = self.source[start..self.current]
+            .chars()
+            .filter(|&c| c != '_')
+            .collect();
+        self.integer(&digits, radix)
+    }
+
+    fn decimal_number(&mut self) -> Result<TokenType> {
+        self.digits(10);
+        let mut is_float = false;
+
         if self.peek() == '.' && Scanner::is_decimal_digit(self.peek_next()) {
+            is_float = true;
             self.advance();
+            self.digits(10);
         }
 
-        while Scanner::is_decimal_digit(self.peek()) {
+        if matches!(self.peek(), 'e' | 'E') {
+            is_float = true;
             self.advance();
+            if matches!(self.peek(), '+' | '-') {
+                self.advance();
+            }
+            if !self.digits(10) {
+                return Err(self
+                    .error(ErrorCode::InvalidNumber, "Expect digits in exponent")
+                    .with_help("exponents are written like 1e9, 2.5E-3 or 4e+2"));
+            }
         }
 
-        let val: f64 = self.source[self.start..self.current].parse().unwrap();
+        let text: String = self.source[self.start..self.current]
+            .chars()
+            .filter(|&c| c != '_')
+            .collect();
+        if !is_float {
+            return self.integer(&text, 10).map(TokenType::Integer);
+        }
+        text.parse()
+            .map(|value| TokenType::Number(Number(value)))
+            .map_err(|_| {
+                self.error(
+                    ErrorCode::InvalidNumber,
+                    format!("Invalid number '{}'", text),
+                )
+            })
+    }
 
-        self.add_token(TokenType::Number(Number(val)));
+    /// Parses the digits of an integer literal. The magnitude of i64::MIN is one
+    /// too large to fit, so it wraps to i64::MIN, which the parser only accepts
+    /// straight after a `-`.
+    fn integer(&self, digits: &str, radix: u32) -> Result<i64> {
+        match u64::from_str_radix(digits, radix) {
+            Ok(n) if n <= i64::MIN.unsigned_abs() => Ok(n as i64),
+            _ => Err(self
+                .error(ErrorCode::InvalidNumber, "Integer literal is too large")
+                .with_help(integer_too_large_help())),
+        }
+    }
+
+    /// Scans digits in `radix`, which can be separated by single underscores,
+    /// returning whether there were any
+    fn digits(&mut self, radix: u32) -> bool {
+        let start = self.current;
+        loop {
+            let c = self.peek();
+            let follows_digit = self.source[..self.current]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_digit(radix));
+            let separator = c == '_' && follows_digit && self.peek_next().is_digit(radix);
+            if !c.is_digit(radix) && !separator {
+                break;
+            }
+            self.advance();
+        }
+        self.current > start
     }
 
     fn string(&mut self) -> Result<()> {
+        let mut value = String::new();
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1
+            if self.peek() == '\\' {
+                value.push(self.escape()?);
+            } else if self.peek() == '$' && self.peek_next() == '{' {
+                let (line, col) = (self.line, self.col + 1);
+                self.advance();
+                self.advance();
+                self.interpolations.push(Interpolation {
+                    span: Span {
+                        line,
+                        col,
+                        start: self.current - 2,
+                        ..self.span()
+                    },
+                    braces: 0,
+                });
+                self.add_token(TokenType::Interpolation(value));
+                return Ok(());
+            } else {
+                value.push(self.advance());
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            return Err(anyhow!("Unterminated string at line {}", self.line));
+            return Err(self
+                .error(ErrorCode::UnterminatedString, "Unterminated string")
+                .with_help("add a closing '\"' to end the string"));
         }
 
         assert!(self.peek() == '"');
 
         self.advance();
 
-        self.add_token(TokenType::String(
-            self.source[self.start + 1..self.current - 1].to_string(),
-        ));
+        self.add_token(TokenType::String(value));
         Ok(())
     }
 
+    /// Scans an escape sequence in a string, returning the character it stands
+    /// for
+    fn escape(&mut self) -> Result<char> {
+        let (start, line, col) = (self.current, self.line, self.col + 1);
+        self.advance();
+        let c = match self.advance() {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            '"' => Some('"'),
+            '\\' => Some('\\'),
+            '$' => Some('$'),
+            'u' if self.matches('{') => {
+                let digits = self.current;
+                while self.peek().is_ascii_hexdigit() {
+                    self.advance();
+                }
+                let digits = &self.source[digits..self.current];
+                match self.matches('}') && digits.len() <= 6 {
+                    true => u32::from_str_radix(digits, 16)
+                        .ok()
+                        .and_then(char::from_u32),
+                    false => None,
+                }
+            }
+            _ => None,
+        };
+
+        c.ok_or_else(|| {
+            let sequence = &self.source[start..self.current];
+            let span = Span {
+                source: self.source_id,
+                line,
+                col,
+                start,
+                end: self.current,
+            };
+            Error::Scan(Box::new(Diagnostic::new(
+                ErrorCode::InvalidEscape,
+                format!("Invalid escape sequence '{}'", sequence),
+                span,
+            )))
+            .with_help(
+                "strings can contain \\n, \\t, \\\", \\\\, \\$ and \\u{...} with 1 to 6 hex digits",
+            )
+        })
+    }
+
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            char::from(self.source.as_bytes()[self.current + 1])
-        }
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            char::from(self.source.as_bytes()[self.current])
-        }
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn matches(&mut self, c: char) -> bool {
-        if self.is_at_end() {
-            return true;
-        }
-
-        if char::from(self.source.as_bytes()[self.current]) != c {
+        if self.is_at_end() || self.peek() != c {
             return false;
         }
 
-        self.current += 1;
-        self.col += 1;
+        self.advance();
         true
     }
 
@@ -337,11 +568,25 @@ impl<'a> Scanner<'a> {
         self.tokens.push(Token {
             token_type,
             lexeme: text.to_string(),
-            line: self.line,
-            col: self.col,
+            span: self.span(),
         })
     }
 
+    /// Span of the current token so far
+    fn span(&self) -> Span {
+        Span {
+            source: self.source_id,
+            line: self.start_line,
+            col: self.start_col,
+            start: self.start,
+            end: self.current,
+        }
+    }
+
+    fn error(&self, code: ErrorCode, message: impl Into<String>) -> Error {
+        Error::Scan(Box::new(Diagnostic::new(code, message, self.span())))
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -349,11 +594,119 @@ impl<'a> Scanner<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::scan_tokens;
+    use super::{scan_tokens, Number, TokenType};
+    use crate::{error::ErrorCode, source::SourceId};
 
     #[test]
     fn it_works() {
-        let tokens = scan_tokens("  ({}) ").unwrap();
+        let tokens = scan_tokens("  ({}) ", SourceId::default()).unwrap();
         assert_eq!(tokens[0].lexeme, "(");
     }
+
+    #[test]
+    fn spans() {
+        let tokens = scan_tokens("var x;\n  print x;", SourceId::default()).unwrap();
+        let print = &tokens[3];
+        assert_eq!(print.lexeme, "print");
+        assert_eq!((print.span.line, print.span.col), (2, 3));
+        assert_eq!((print.span.start, print.span.end), (9, 14));
+    }
+
+    #[test]
+    fn errors() {
+        let error = scan_tokens("var x = \"open;", SourceId::default()).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::UnterminatedString);
+
+        let error = scan_tokens("\n  @", SourceId::default()).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::UnexpectedCharacter);
+        assert_eq!((error.span().line, error.span().col), (2, 3));
+
+        let error = scan_tokens("\"\\u{d800}\"", SourceId::default()).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidEscape);
+        assert_eq!((error.span().start, error.span().end), (1, 9));
+    }
+
+    #[test]
+    fn escapes() {
+        let tokens = scan_tokens(r#""a\tb\n\"\\\u{e9}\u{1F600}""#, SourceId::default()).unwrap();
+        assert_eq!(
+            tokens[0].token_type,
+            TokenType::String("a\tb\n\"\\é😀".to_string())
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        let tokens = scan_tokens(
+            "0xFF 0B1010 1_000_000 3.0 1e-9 2.5E10 0.5_5",
+            SourceId::default(),
+        )
+        .unwrap();
+        let types: Vec<TokenType> = tokens.into_iter().map(|token| token.token_type).collect();
+        assert_eq!(
+            types,
+            [
+                TokenType::Integer(255),
+                TokenType::Integer(10),
+                TokenType::Integer(1_000_000),
+                TokenType::Number(Number(3.0)),
+                TokenType::Number(Number(1e-9)),
+                TokenType::Number(Number(2.5e10)),
+                TokenType::Number(Number(0.55)),
+                TokenType::Eof,
+            ]
+        );
+
+        for (source, message, end) in [
+            ("0x;", "Expect hex digits after '0x'", 2),
+            ("1e", "Expect digits in exponent", 2),
+            ("0b12", "Unexpected character '2' in number", 4),
+            ("1__0", "Unexpected character '_' in number", 2),
+            ("9223372036854775809", "Integer literal is too large", 19),
+            (
+                "0x1_0000_0000_0000_0000",
+                "Integer literal is too large",
+                23,
+            ),
+        ] {
+            let error = scan_tokens(source, SourceId::default()).unwrap_err();
+            assert_eq!(error.code(), ErrorCode::InvalidNumber);
+            assert_eq!(error.message(), message);
+            assert_eq!((error.span().start, error.span().end), (0, end));
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        let tokens = scan_tokens("\"a${ {b: 1} }c\"", SourceId::default()).unwrap();
+        let types: Vec<_> = tokens.iter().map(|token| &token.token_type).collect();
+        assert_eq!(
+            types[..],
+            [
+                &TokenType::Interpolation("a".to_string()),
+                &TokenType::LeftBrace,
+                &TokenType::Identifier,
+                &TokenType::Colon,
+                &TokenType::Integer(1),
+                &TokenType::RightBrace,
+                &TokenType::String("c".to_string()),
+                &TokenType::Eof,
+            ]
+        );
+
+        let error = scan_tokens("\"a${b", SourceId::default()).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::UnterminatedInterpolation);
+        assert_eq!((error.span().start, error.span().end), (2, 4));
+    }
+
+    #[test]
+    fn unicode() {
+        let tokens = scan_tokens("\"ü\nß\" café_1;", SourceId::default()).unwrap();
+        assert_eq!(tokens[0].token_type, TokenType::String("ü\nß".to_string()));
+        // Columns count characters, from the start of the string's last line
+        let name = &tokens[1];
+        assert_eq!(name.lexeme, "café_1");
+        assert_eq!((name.span.line, name.span.col), (2, 4));
+        assert_eq!((tokens[2].span.line, tokens[2].span.col), (2, 10));
+    }
 }
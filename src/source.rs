@@ -0,0 +1,43 @@
+/// Identifies a piece of source code held by a SourceMap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SourceId(pub u32);
+
+pub struct Source {
+    /// Path of the file, or a placeholder such as `<prompt>`
+    pub name: String,
+    pub text: String,
+}
+
+/// Every piece of source code which has been loaded, so that diagnostics can
+/// show the code they point at
+#[derive(Default)]
+pub struct SourceMap {
+    sources: Vec<Source>,
+}
+
+impl SourceMap {
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> SourceId {
+        self.sources.push(Source {
+            name: name.into(),
+            text: text.into(),
+        });
+        SourceId((self.sources.len() - 1).try_into().unwrap())
+    }
+
+    pub fn get(&self, id: SourceId) -> Option<&Source> {
+        self.sources.get(id.0 as usize)
+    }
+}
+
+impl Source {
+    /// The full line containing byte offset `offset`, along with the offset the
+    /// line starts at
+    pub fn line_at(&self, offset: usize) -> (&str, usize) {
+        let offset = offset.min(self.text.len());
+        let start = self.text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = self.text[offset..]
+            .find('\n')
+            .map_or(self.text.len(), |i| offset + i);
+        (self.text[start..end].trim_end_matches('\r'), start)
+    }
+}
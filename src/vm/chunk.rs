@@ -0,0 +1,126 @@
+use std::rc::Rc;
+
+use super::object::CompiledFunction;
+use crate::{error::Span, runtime::string::Str};
+
+/// A single instruction. Operands index the chunk's constants, the current
+/// frame's locals or upvalues, or are jump distances counted in instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Constant(u16),
+    Nil,
+    True,
+    False,
+    Pop,
+
+    GetLocal(u16),
+    SetLocal(u16),
+    GetUpvalue(u16),
+    SetUpvalue(u16),
+    /// Operands of the global ops are the name's constant
+    GetGlobal(u16),
+    SetGlobal(u16),
+    DefineGlobal(u16),
+    /// Declares a global without a value, like `var a;`
+    DeclareGlobal(u16),
+
+    GetProperty(u16),
+    SetProperty(u16),
+    GetIndex,
+    SetIndex,
+
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Not,
+    Negate,
+
+    Print,
+    Jump(u16),
+    /// Jumps if the top of the stack is falsey, leaving it there
+    JumpIfFalse(u16),
+    /// Jumps backwards
+    Loop(u16),
+    /// Calls the value below this many arguments
+    Call(u8),
+    /// Calls like Call, then returns what it returns. The call takes the place
+    /// of the current frame, which has nothing left to do.
+    TailCall(u8),
+    /// Wraps the function constant in a closure, capturing its upvalues
+    Closure(u16),
+    /// Pops a local which has been captured by a closure
+    CloseUpvalue,
+    /// Pops every local from this slot up, closing those which are captured
+    Unwind(u16),
+    Return,
+
+    /// Builds a class from this many method closures on the stack
+    Class {
+        name: u16,
+        methods: u16,
+    },
+    List(u16),
+    /// Joins this many values on the stack into a string, formatted as they'd
+    /// be printed
+    Interpolate(u16),
+    /// Builds a map from this many key and value pairs on the stack
+    Map(u16),
+
+    /// Pushes the module at the path constant, running it if needed
+    Import(u16),
+    /// Pops a module and defines all of its exports as globals
+    ImportAll,
+
+    Throw,
+    /// Catches errors raised until the matching PopHandler, jumping forward to
+    /// the catch clause with the caught value on the stack
+    PushCatch(u16),
+    /// Like PushCatch, but the error is put aside while jumping to the finally
+    /// block, to be raised again by EndFinally
+    PushFinally(u16),
+    PopHandler,
+    EndFinally,
+    /// Drops the error put aside by the innermost finally block, which is
+    /// jumping elsewhere instead of raising it again
+    PopPending,
+}
+
+#[derive(Debug)]
+pub enum Constant {
+    Integer(i64),
+    Number(f64),
+    String(Str),
+    Function(Rc<CompiledFunction>),
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// Source of each instruction, which errors it raises are reported at
+    pub spans: Vec<Span>,
+    pub constants: Vec<Constant>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, op: Op, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    /// Name stored in the constant at `index`
+    pub fn name(&self, index: u16) -> &str {
+        match &self.constants[index as usize] {
+            Constant::String(name) => name,
+            _ => unreachable!("Names are always string constants"),
+        }
+    }
+}
@@ -0,0 +1,922 @@
+use std::{collections::HashMap, rc::Rc};
+
+use super::{
+    chunk::{Chunk, Constant, Op},
+    object::{CompiledFunction, FunctionKind, UpvalueRef},
+};
+use crate::{
+    ast::{
+        expr::{Expr, ExprId, Literal},
+        stmt::{CatchClause, FunctionDecl, Stmt},
+    },
+    error::{compile_error, Error, ErrorCode, Result, Span},
+    resolver::Locals,
+    runtime::string::Str,
+    scanner::{Number, Token, TokenType},
+};
+
+/// Compiles a resolved script to the function which runs it
+pub fn compile(statements: &[Stmt], locals: &Locals) -> Result<Rc<CompiledFunction>> {
+    let mut compiler = Compiler {
+        resolved: locals,
+        states: vec![FunctionState::new(None, FunctionKind::Script, 0)],
+        span: Span::default(),
+    };
+    compiler.statements(statements)?;
+    Ok(compiler.end_function())
+}
+
+struct Local {
+    name: String,
+    depth: u32,
+    /// Whether a closure captures the local, so it needs closing rather than
+    /// just popping when it goes out of scope
+    captured: bool,
+}
+
+/// A statement which code inside it may jump out of
+enum Control<'a> {
+    Loop {
+        /// Number of locals in scope outside the loop body
+        locals: usize,
+        breaks: Vec<usize>,
+        continues: Vec<usize>,
+    },
+    /// The body of a try statement with a catch clause
+    Catch,
+    /// The body or catch clause of a try statement with a finally block
+    Finally { locals: usize, body: &'a [Stmt] },
+    /// A finally block run because of an error, which is raised again at its
+    /// end
+    Pending,
+}
+
+/// A function which is part way through being compiled
+struct FunctionState<'a> {
+    function: CompiledFunction,
+    locals: Vec<Local>,
+    scope_depth: u32,
+    controls: Vec<Control<'a>>,
+    /// Constant of each name the function has used
+    names: HashMap<String, u16>,
+}
+
+impl FunctionState<'_> {
+    fn new(name: Option<String>, kind: FunctionKind, arity: u8) -> Self {
+        // Slot 0 holds the receiver of methods, otherwise the function being
+        // called which can't be referred to by name
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        Self {
+            function: CompiledFunction {
+                name,
+                kind,
+                arity,
+                chunk: Chunk::default(),
+                upvalues: vec![],
+            },
+            locals: vec![Local {
+                name: receiver.to_owned(),
+                depth: 0,
+                captured: false,
+            }],
+            scope_depth: 0,
+            controls: vec![],
+            names: HashMap::new(),
+        }
+    }
+}
+
+struct Compiler<'a> {
+    resolved: &'a Locals,
+    // Functions being compiled, innermost last
+    states: Vec<FunctionState<'a>>,
+    // Source of the code being compiled, which emitted instructions are
+    // attributed to
+    span: Span,
+}
+
+impl<'a> Compiler<'a> {
+    fn statements(&mut self, statements: &'a [Stmt]) -> Result<()> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, statements: &'a [Stmt]) -> Result<()> {
+        self.begin_scope();
+        self.statements(statements)?;
+        self.end_scope();
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &'a Stmt) -> Result<()> {
+        match statement {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Break { keyword } => {
+                self.span = keyword.span;
+                self.exit_loop(true)
+            }
+            Stmt::ClassDecl { name, methods } => {
+                self.span = name.span;
+                self.declare(name)?;
+                for method in methods {
+                    let kind = if method.declared_name().lexeme == "init" {
+                        FunctionKind::Initializer
+                    } else {
+                        FunctionKind::Method
+                    };
+                    self.function(method, kind)?;
+                }
+                let class = self.name_constant(&name.lexeme)?;
+                let methods = self.count(methods.len(), "Too many methods in one class")?;
+                self.emit_at(
+                    Op::Class {
+                        name: class,
+                        methods,
+                    },
+                    name,
+                );
+                self.define(name)
+            }
+            Stmt::Continue { keyword } => {
+                self.span = keyword.span;
+                self.exit_loop(false)
+            }
+            Stmt::Expression(expression) => {
+                self.expression(expression)?;
+                self.emit(Op::Pop);
+                Ok(())
+            }
+            Stmt::FunctionDecl(declaration) => {
+                let name = declaration.declared_name();
+                self.span = name.span;
+                self.declare(name)?;
+                self.function(declaration, FunctionKind::Function)?;
+                self.define(name)
+            }
+            Stmt::Import {
+                keyword,
+                path,
+                name,
+            } => {
+                self.span = keyword.span;
+                let path = self.make_constant(Constant::String(Str::intern(path)))?;
+                self.emit(Op::Import(path));
+                match name {
+                    Some(name) => {
+                        self.declare(name)?;
+                        self.define(name)
+                    }
+                    None => {
+                        self.emit(Op::ImportAll);
+                        Ok(())
+                    }
+                }
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition)?;
+                let then_jump = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.statement(then_branch)?;
+                let else_jump = self.emit(Op::Jump(0));
+                self.patch_jump(then_jump)?;
+                self.emit(Op::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch)?;
+                }
+                self.patch_jump(else_jump)
+            }
+            Stmt::Print(expression) => {
+                self.expression(expression)?;
+                self.emit(Op::Print);
+                Ok(())
+            }
+            Stmt::Return { keyword, value } => {
+                self.span = keyword.span;
+                match value {
+                    Some(Expr::Call {
+                        callee,
+                        paren,
+                        arguments,
+                    }) if self.in_tail_position() => {
+                        self.expression(callee)?;
+                        for argument in arguments {
+                            self.expression(argument)?;
+                        }
+                        let count = arguments.len().try_into().unwrap();
+                        self.emit_at(Op::TailCall(count), paren);
+                        return Ok(());
+                    }
+                    Some(value) => self.expression(value)?,
+                    None => self.emit_default_return_value(),
+                }
+                self.emit_return()
+            }
+            Stmt::Throw { keyword, value } => {
+                self.expression(value)?;
+                self.emit_at(Op::Throw, keyword);
+                Ok(())
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => self.try_statement(body, catch.as_ref(), finally.as_deref()),
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
+                let start = self.chunk().code.len();
+                self.expression(condition)?;
+                let exit = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+
+                let locals = self.state().locals.len();
+                self.state().controls.push(Control::Loop {
+                    locals,
+                    breaks: vec![],
+                    continues: vec![],
+                });
+                self.statement(body)?;
+                let Some(Control::Loop {
+                    breaks, continues, ..
+                }) = self.state().controls.pop()
+                else {
+                    unreachable!("Controls are pushed and popped in pairs")
+                };
+
+                for jump in continues {
+                    self.patch_jump(jump)?;
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment)?;
+                    self.emit(Op::Pop);
+                }
+                self.emit_loop(start)?;
+
+                self.patch_jump(exit)?;
+                self.emit(Op::Pop);
+                for jump in breaks {
+                    self.patch_jump(jump)?;
+                }
+                Ok(())
+            }
+            Stmt::VarDecl { name, initializer } => {
+                self.span = name.span;
+                self.declare(name)?;
+                match initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    // Globals declared without a value stay undefined until they're
+                    // assigned, like in the tree-walker
+                    None if self.state().scope_depth == 0 => {
+                        let global = self.name_constant(&name.lexeme)?;
+                        self.emit(Op::DeclareGlobal(global));
+                        return Ok(());
+                    }
+                    None => {
+                        self.emit(Op::Nil);
+                    }
+                }
+                self.define(name)
+            }
+        }
+    }
+
+    /// Compiles a try statement as a finally handler wrapped around a catch
+    /// handler, either of which may be missing
+    fn try_statement(
+        &mut self,
+        body: &'a [Stmt],
+        catch: Option<&'a CatchClause>,
+        finally: Option<&'a [Stmt]>,
+    ) -> Result<()> {
+        let finally_handler = match finally {
+            Some(finally) => {
+                let handler = self.emit(Op::PushFinally(0));
+                let locals = self.state().locals.len();
+                self.state().controls.push(Control::Finally {
+                    locals,
+                    body: finally,
+                });
+                Some((handler, finally))
+            }
+            None => None,
+        };
+
+        match catch {
+            Some(catch) => {
+                let handler = self.emit(Op::PushCatch(0));
+                self.state().controls.push(Control::Catch);
+                self.block(body)?;
+                self.state().controls.pop();
+                self.emit(Op::PopHandler);
+                let skip = self.emit(Op::Jump(0));
+
+                // The caught value is pushed into the slot of the catch variable
+                self.patch_jump(handler)?;
+                self.begin_scope();
+                self.span = catch.name.span;
+                self.add_local(&catch.name.lexeme)?;
+                self.statements(&catch.body)?;
+                self.end_scope();
+                self.patch_jump(skip)?;
+            }
+            None => self.block(body)?,
+        }
+
+        if let Some((handler, finally)) = finally_handler {
+            self.state().controls.pop();
+            self.emit(Op::PopHandler);
+            self.block(finally)?;
+            let skip = self.emit(Op::Jump(0));
+
+            self.patch_jump(handler)?;
+            self.state().controls.push(Control::Pending);
+            self.block(finally)?;
+            self.state().controls.pop();
+            self.emit(Op::EndFinally);
+            self.patch_jump(skip)?;
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &'a Expr) -> Result<()> {
+        match expression {
+            Expr::Assign { id, name, value } => {
+                self.expression(value)?;
+                self.variable(*id, name, true)
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                let op = match operator.token_type {
+                    TokenType::Minus => Op::Subtract,
+                    TokenType::Slash => Op::Divide,
+                    TokenType::Percent => Op::Modulo,
+                    TokenType::Star => Op::Multiply,
+                    TokenType::Plus => Op::Add,
+                    TokenType::Greater => Op::Greater,
+                    TokenType::GreaterEqual => Op::GreaterEqual,
+                    TokenType::Less => Op::Less,
+                    TokenType::LessEqual => Op::LessEqual,
+                    TokenType::BangEqual => Op::NotEqual,
+                    TokenType::EqualEqual => Op::Equal,
+                    _ => unreachable!(),
+                };
+                self.emit_at(op, operator);
+                Ok(())
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                self.expression(callee)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                // The parser limits the number of arguments
+                self.emit_at(Op::Call(arguments.len().try_into().unwrap()), paren);
+                Ok(())
+            }
+            Expr::Get { object, name } => {
+                self.expression(object)?;
+                self.span = name.span;
+                let name = self.name_constant(&name.lexeme)?;
+                self.emit(Op::GetProperty(name));
+                Ok(())
+            }
+            Expr::Grouping(expression) => self.expression(expression),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.emit_at(Op::GetIndex, bracket);
+                Ok(())
+            }
+            Expr::IndexSet {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+                self.emit_at(Op::SetIndex, bracket);
+                Ok(())
+            }
+            Expr::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part)?;
+                }
+                let count = self.count(parts.len(), "Too many parts in interpolated string")?;
+                self.emit(Op::Interpolate(count));
+                Ok(())
+            }
+            Expr::Lambda(declaration) => self.function(declaration, FunctionKind::Function),
+            Expr::List(elements) => {
+                for element in elements {
+                    self.expression(element)?;
+                }
+                let count = self.count(elements.len(), "Too many elements in list")?;
+                self.emit(Op::List(count));
+                Ok(())
+            }
+            Expr::Map { brace, entries } => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+                self.span = brace.span;
+                let count = self.count(entries.len(), "Too many entries in map")?;
+                self.emit(Op::Map(count));
+                Ok(())
+            }
+            Expr::Literal(literal) => {
+                match literal {
+                    Literal::Integer(n) => self.emit_constant(Constant::Integer(*n))?,
+                    Literal::Number(Number(n)) => self.emit_constant(Constant::Number(*n))?,
+                    Literal::String(s) => self.emit_constant(Constant::String(s.clone()))?,
+                    Literal::True => {
+                        self.emit(Op::True);
+                    }
+                    Literal::False => {
+                        self.emit(Op::False);
+                    }
+                    Literal::Nil => {
+                        self.emit(Op::Nil);
+                    }
+                }
+                Ok(())
+            }
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left)?;
+                match operator.token_type {
+                    TokenType::And => {
+                        let end = self.emit(Op::JumpIfFalse(0));
+                        self.emit(Op::Pop);
+                        self.expression(right)?;
+                        self.patch_jump(end)
+                    }
+                    TokenType::Or => {
+                        let else_jump = self.emit(Op::JumpIfFalse(0));
+                        let end = self.emit(Op::Jump(0));
+                        self.patch_jump(else_jump)?;
+                        self.emit(Op::Pop);
+                        self.expression(right)?;
+                        self.patch_jump(end)
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(value)?;
+                self.span = name.span;
+                let name = self.name_constant(&name.lexeme)?;
+                self.emit(Op::SetProperty(name));
+                Ok(())
+            }
+            Expr::This { id, keyword } => self.variable(*id, keyword, false),
+            Expr::Unary { operator, right } => {
+                self.expression(right)?;
+                let op = match operator.token_type {
+                    TokenType::Minus => Op::Negate,
+                    TokenType::Bang => Op::Not,
+                    _ => unreachable!(),
+                };
+                self.emit_at(op, operator);
+                Ok(())
+            }
+            Expr::Variable { id, name } => self.variable(*id, name, false),
+        }
+    }
+
+    /// Compiles a function and emits the closure which wraps it
+    fn function(&mut self, declaration: &'a FunctionDecl, kind: FunctionKind) -> Result<()> {
+        let span = self.span;
+        let name = declaration.name.as_ref().map(|name| name.lexeme.clone());
+        let arity = declaration.params.len().try_into().unwrap();
+        self.states.push(FunctionState::new(name, kind, arity));
+
+        self.begin_scope();
+        for param in &declaration.params {
+            self.span = param.span;
+            self.add_local(&param.lexeme)?;
+        }
+        self.statements(&declaration.body)?;
+        let function = self.end_function();
+
+        self.span = span;
+        let function = self.make_constant(Constant::Function(function))?;
+        self.emit(Op::Closure(function));
+        Ok(())
+    }
+
+    fn end_function(&mut self) -> Rc<CompiledFunction> {
+        self.emit_default_return_value();
+        self.emit(Op::Return);
+        Rc::new(self.states.pop().unwrap().function)
+    }
+
+    /// Emits what a bare `return` returns, which is `this` in initializers
+    fn emit_default_return_value(&mut self) {
+        if self.state().function.kind == FunctionKind::Initializer {
+            self.emit(Op::GetLocal(0));
+        } else {
+            self.emit(Op::Nil);
+        }
+    }
+
+    /// Returns the value on top of the stack, first running the finally blocks
+    /// of any try statements being returned from
+    fn emit_return(&mut self) -> Result<()> {
+        let finally = self
+            .state()
+            .controls
+            .iter()
+            .any(|control| matches!(control, Control::Finally { .. }));
+        // Keep the value in a slot of its own while the finally blocks run
+        if finally {
+            self.add_local("")?;
+        }
+        self.exit_controls(0)?;
+        if finally {
+            self.state().locals.pop();
+        }
+        self.emit(Op::Return);
+        Ok(())
+    }
+
+    /// Whether a call being returned would be the last thing the function does,
+    /// which it isn't inside a try statement
+    fn in_tail_position(&mut self) -> bool {
+        self.state()
+            .controls
+            .iter()
+            .all(|control| matches!(control, Control::Loop { .. }))
+    }
+
+    /// Jumps to the end of the innermost loop, or the start of its next
+    /// iteration
+    fn exit_loop(&mut self, is_break: bool) -> Result<()> {
+        let index = self
+            .state()
+            .controls
+            .iter()
+            .rposition(|control| matches!(control, Control::Loop { .. }))
+            .expect("The resolver only allows break and continue in loops");
+        self.exit_controls(index + 1)?;
+
+        let Control::Loop { locals, .. } = self.state().controls[index] else {
+            unreachable!()
+        };
+        if self.state().locals.len() > locals {
+            self.emit(Op::Unwind(locals as u16));
+        }
+        let jump = self.emit(Op::Jump(0));
+        if let Control::Loop {
+            breaks, continues, ..
+        } = &mut self.state().controls[index]
+        {
+            if is_break {
+                breaks.push(jump);
+            } else {
+                continues.push(jump);
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves every control statement from the innermost out to the one at
+    /// `depth`, removing their handlers and running their finally blocks
+    fn exit_controls(&mut self, depth: usize) -> Result<()> {
+        let mut i = self.state().controls.len();
+        while i > depth {
+            i -= 1;
+            match self.state().controls[i] {
+                Control::Loop { .. } => {}
+                Control::Catch => {
+                    self.emit(Op::PopHandler);
+                }
+                Control::Pending => {
+                    self.emit(Op::PopPending);
+                }
+                Control::Finally { locals, body } => {
+                    self.emit(Op::PopHandler);
+                    // The finally block is compiled as if it followed the try
+                    // statement, so it can't see the locals or controls inside it
+                    let inner = self.state().controls.split_off(i);
+                    let hidden: Vec<String> = self.state().locals[locals..]
+                        .iter_mut()
+                        .map(|local| std::mem::take(&mut local.name))
+                        .collect();
+                    self.block(body)?;
+                    for (local, name) in self.state().locals[locals..].iter_mut().zip(hidden) {
+                        local.name = name;
+                    }
+                    self.state().controls.extend(inner);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn variable(&mut self, id: ExprId, name: &Token, assign: bool) -> Result<()> {
+        self.span = name.span;
+        let current = self.states.len() - 1;
+        // The resolver has already worked out whether the variable is local, so
+        // it only needs finding
+        if self.resolved.contains_key(&id) {
+            if let Some(slot) = self.resolve_local(current, &name.lexeme) {
+                self.emit(if assign {
+                    Op::SetLocal(slot)
+                } else {
+                    Op::GetLocal(slot)
+                });
+                return Ok(());
+            }
+            if let Some(index) = self.resolve_upvalue(current, &name.lexeme)? {
+                self.emit(if assign {
+                    Op::SetUpvalue(index)
+                } else {
+                    Op::GetUpvalue(index)
+                });
+                return Ok(());
+            }
+        }
+
+        let global = self.name_constant(&name.lexeme)?;
+        self.emit(if assign {
+            Op::SetGlobal(global)
+        } else {
+            Op::GetGlobal(global)
+        });
+        Ok(())
+    }
+
+    fn resolve_local(&self, state: usize, name: &str) -> Option<u16> {
+        self.states[state]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u16)
+    }
+
+    /// Finds a variable in the functions enclosing the one at `state`, adding
+    /// upvalues to capture it along the way
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Result<Option<u16>> {
+        if state == 0 {
+            return Ok(None);
+        }
+        let enclosing = state - 1;
+
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.states[enclosing].locals[slot as usize].captured = true;
+            let upvalue = UpvalueRef {
+                is_local: true,
+                index: slot,
+            };
+            return self.add_upvalue(state, upvalue).map(Some);
+        }
+        if let Some(index) = self.resolve_upvalue(enclosing, name)? {
+            let upvalue = UpvalueRef {
+                is_local: false,
+                index,
+            };
+            return self.add_upvalue(state, upvalue).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, state: usize, upvalue: UpvalueRef) -> Result<u16> {
+        let upvalues = &mut self.states[state].function.upvalues;
+        if let Some(index) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(index as u16);
+        }
+        if upvalues.len() >= u16::MAX as usize {
+            return Err(compile_error(
+                self.span,
+                ErrorCode::TooManyUpvalues,
+                "Too many closure variables in function",
+            ));
+        }
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u16)
+    }
+
+    /// Declares a variable in the current scope. Locals are in scope from here
+    /// on, like in the resolver, whereas globals only exist once defined.
+    fn declare(&mut self, name: &Token) -> Result<()> {
+        if self.state().scope_depth > 0 {
+            self.add_local(&name.lexeme)?;
+        }
+        Ok(())
+    }
+
+    /// Gives a variable the value on top of the stack, which for locals is
+    /// already in their slot
+    fn define(&mut self, name: &Token) -> Result<()> {
+        if self.state().scope_depth == 0 {
+            self.span = name.span;
+            let global = self.name_constant(&name.lexeme)?;
+            self.emit(Op::DefineGlobal(global));
+        }
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: &str) -> Result<()> {
+        if self.state().locals.len() >= u16::MAX as usize {
+            return Err(self.error(
+                ErrorCode::TooManyLocals,
+                "Too many local variables in function",
+            ));
+        }
+        let depth = self.state().scope_depth;
+        self.state().locals.push(Local {
+            name: name.to_owned(),
+            depth,
+            captured: false,
+        });
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let span = self.span;
+        let state = self.state();
+        state.scope_depth -= 1;
+        while let Some(local) = state.locals.last() {
+            if local.depth <= state.scope_depth {
+                break;
+            }
+            let op = if local.captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            };
+            state.function.chunk.write(op, span);
+            state.locals.pop();
+        }
+    }
+
+    fn state(&mut self) -> &mut FunctionState<'a> {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let span = self.span;
+        self.chunk().write(op, span)
+    }
+
+    fn emit_at(&mut self, op: Op, at: impl Into<Span>) -> usize {
+        self.span = at.into();
+        self.emit(op)
+    }
+
+    fn emit_constant(&mut self, constant: Constant) -> Result<()> {
+        let constant = self.make_constant(constant)?;
+        self.emit(Op::Constant(constant));
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, start: usize) -> Result<()> {
+        let distance = self.chunk().code.len() + 1 - start;
+        let offset = self.jump_offset(distance)?;
+        self.emit(Op::Loop(offset));
+        Ok(())
+    }
+
+    /// Points the forward jump at `jump` to the next instruction
+    fn patch_jump(&mut self, jump: usize) -> Result<()> {
+        let distance = self.chunk().code.len() - jump - 1;
+        let offset = self.jump_offset(distance)?;
+        let code = &mut self.chunk().code;
+        code[jump] = match code[jump] {
+            Op::Jump(_) => Op::Jump(offset),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(offset),
+            Op::PushCatch(_) => Op::PushCatch(offset),
+            Op::PushFinally(_) => Op::PushFinally(offset),
+            op => unreachable!("{:?} doesn't jump", op),
+        };
+        Ok(())
+    }
+
+    fn jump_offset(&self, distance: usize) -> Result<u16> {
+        u16::try_from(distance)
+            .map_err(|_| self.error(ErrorCode::JumpTooLarge, "Too much code to jump over"))
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> Result<u16> {
+        let index = u16::try_from(self.chunk().constants.len()).map_err(|_| {
+            self.error(
+                ErrorCode::TooManyConstants,
+                "Too many constants in one function",
+            )
+        })?;
+        self.chunk().constants.push(constant);
+        Ok(index)
+    }
+
+    fn name_constant(&mut self, name: &str) -> Result<u16> {
+        if let Some(index) = self.state().names.get(name) {
+            return Ok(*index);
+        }
+        let index = self.make_constant(Constant::String(Str::intern(name)))?;
+        self.state().names.insert(name.to_owned(), index);
+        Ok(index)
+    }
+
+    /// Checks the number of operands an instruction builds a value from fits
+    /// in it
+    fn count(&self, count: usize, message: &str) -> Result<u16> {
+        u16::try_from(count).map_err(|_| self.error(ErrorCode::TooManyElements, message))
+    }
+
+    fn error(&self, code: ErrorCode, message: &str) -> Error {
+        compile_error(self.span, code, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::{
+        parser::Parser,
+        resolver::Resolver,
+        scanner::scan_tokens,
+        source::SourceId,
+        vm::{chunk::Op, object::CompiledFunction},
+    };
+
+    fn compile_source(source: &str) -> std::rc::Rc<CompiledFunction> {
+        let tokens = scan_tokens(source, SourceId::default()).unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let locals = Resolver::new().resolve(&statements).unwrap();
+        compile(&statements, &locals).unwrap()
+    }
+
+    #[test]
+    fn locals_use_slots() {
+        let script = compile_source("{ var a = 1; print a; }");
+        assert_eq!(
+            script.chunk.code,
+            vec![
+                Op::Constant(0),
+                Op::GetLocal(1),
+                Op::Print,
+                Op::Pop,
+                Op::Nil,
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_jump_back_to_condition() {
+        let script = compile_source("var i = 0; while (i < 2) i = i + 1;");
+        let code = &script.chunk.code;
+        let condition = 2;
+        assert_eq!(code[condition], Op::GetGlobal(1));
+        let loop_at = code
+            .iter()
+            .position(|op| matches!(op, Op::Loop(_)))
+            .unwrap();
+        assert_eq!(code[loop_at], Op::Loop((loop_at + 1 - condition) as u16));
+        // The condition's false branch lands on the Pop after the loop
+        assert_eq!(code[5], Op::JumpIfFalse((loop_at - 5) as u16));
+        assert_eq!(code[loop_at + 1], Op::Pop);
+    }
+}
@@ -0,0 +1,724 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use super::{
+    chunk::{Constant, Op},
+    compiler,
+    object::{Closure, CompiledFunction, FunctionKind, Upvalue},
+};
+use crate::{
+    error::{
+        runtime_error, stack_overflow_error, thrown_error, Error, ErrorCode, ErrorLocation, Fault,
+        Result, Span,
+    },
+    runtime::{
+        class::{caught_value, Class, Instance, Method},
+        environment::Environment,
+        function::{CallFrame, NativeContext, DEFAULT_MAX_DEPTH},
+        gc,
+        loader::{Import, Loader},
+        map::MapKey,
+        module::Module,
+        native, ops,
+        value::Value,
+    },
+};
+
+/// A call to a closure which hasn't returned yet
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Index of the stack slot holding the callee or receiver, which the
+    /// frame's locals are numbered from
+    base: usize,
+    /// Class being instantiated when the frame runs its initializer
+    class: Option<Rc<Class>>,
+}
+
+#[derive(Clone, Copy)]
+enum HandlerKind {
+    Catch,
+    Finally,
+}
+
+/// Where to resume when an error is raised inside a try statement
+struct Handler {
+    kind: HandlerKind,
+    frame: usize,
+    stack_len: usize,
+    pending_len: usize,
+    target: usize,
+}
+
+/// Runs compiled programs on a stack of values
+pub struct Vm {
+    // Globals of the main script
+    globals: Rc<RefCell<Environment>>,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    // Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // Active try statements, innermost last
+    handlers: Vec<Handler>,
+    // Errors put aside while finally blocks run, innermost last
+    pending: Vec<Error>,
+    // Native function currently being called
+    native_call: Option<CallFrame>,
+    // Class of the values runtime errors are caught as
+    error_class: Rc<Class>,
+    loader: Loader,
+    // Calls can't nest any deeper than this
+    max_depth: usize,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self {
+            globals: gc::environment(native::new_globals()),
+            stack: vec![],
+            frames: vec![],
+            open_upvalues: vec![],
+            handlers: vec![],
+            pending: vec![],
+            native_call: None,
+            error_class: Rc::new(Class {
+                name: "Error".to_string(),
+                methods: HashMap::new(),
+            }),
+            loader: Loader::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn loader(&self) -> &Loader {
+        &self.loader
+    }
+
+    pub fn loader_mut(&mut self) -> &mut Loader {
+        &mut self.loader
+    }
+
+    pub fn interpret(&mut self, script: Rc<CompiledFunction>) -> Result<()> {
+        let closure = Closure {
+            function: script,
+            upvalues: vec![],
+            globals: self.globals.clone(),
+        };
+        self.run_script(gc::closure(closure))
+    }
+
+    fn run_script(&mut self, closure: Rc<Closure>) -> Result<()> {
+        self.stack.push(Value::Closure(closure.clone()));
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base: self.stack.len() - 1,
+            class: None,
+        });
+        self.run(self.frames.len() - 1)?;
+        Ok(())
+    }
+
+    /// Runs until the frame at `base_frame` returns, handling errors raised
+    /// within it
+    fn run(&mut self, base_frame: usize) -> Result<Value> {
+        let pending_len = self.pending.len();
+        loop {
+            match self.execute(base_frame) {
+                Ok(value) => return Ok(value),
+                Err(error) => {
+                    if let Err(error) = self.raise(error, base_frame) {
+                        self.pending.truncate(pending_len);
+                        return Err(error);
+                    }
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, base_frame: usize) -> Result<Value> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.closure.function.chunk.code[frame.ip];
+            frame.ip += 1;
+
+            match op {
+                Op::Constant(index) => {
+                    let value = match &self.frame().closure.function.chunk.constants[index as usize]
+                    {
+                        Constant::Integer(n) => Value::Integer(*n),
+                        Constant::Number(n) => Value::Number(*n),
+                        Constant::String(s) => Value::String(s.clone()),
+                        Constant::Function(_) => unreachable!("Functions are loaded by Closure"),
+                    };
+                    self.stack.push(value);
+                }
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Boolean(true)),
+                Op::False => self.stack.push(Value::Boolean(false)),
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack[self.frame().base + slot as usize].clone();
+                    self.stack.push(value);
+                }
+                Op::SetLocal(slot) => {
+                    let slot = self.frame().base + slot as usize;
+                    self.stack[slot] = self.peek().clone();
+                }
+                Op::GetUpvalue(index) => {
+                    let upvalue = self.frame().closure.upvalues[index as usize].clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(index) => {
+                    let value = self.peek().clone();
+                    let upvalue = self.frame().closure.upvalues[index as usize].clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Op::GetGlobal(name) => {
+                    let closure = &self.frame().closure;
+                    let value = closure
+                        .globals
+                        .borrow()
+                        .get(closure.function.chunk.name(name))
+                        .at(self.span())?;
+                    self.stack.push(value);
+                }
+                Op::SetGlobal(name) => {
+                    let value = self.peek().clone();
+                    let closure = &self.frame().closure;
+                    closure
+                        .globals
+                        .borrow_mut()
+                        .assign(closure.function.chunk.name(name), value)
+                        .at(self.span())?;
+                }
+                Op::DefineGlobal(name) => {
+                    let value = self.pop();
+                    let closure = &self.frame().closure;
+                    closure
+                        .globals
+                        .borrow_mut()
+                        .define(closure.function.chunk.name(name), Some(value));
+                }
+                Op::DeclareGlobal(name) => {
+                    let closure = &self.frame().closure;
+                    closure
+                        .globals
+                        .borrow_mut()
+                        .define(closure.function.chunk.name(name), None);
+                }
+                Op::GetProperty(name) => {
+                    let object = self.pop();
+                    let name = self.frame().closure.function.chunk.name(name);
+                    let value = ops::get_property(&object, name).at(self.span())?;
+                    self.stack.push(value);
+                }
+                Op::SetProperty(name) => {
+                    let value = self.pop();
+                    let object = self.pop();
+                    let name = self.frame().closure.function.chunk.name(name);
+                    let value = ops::set_property(&object, name, value).at(self.span())?;
+                    self.stack.push(value);
+                }
+                Op::GetIndex => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = ops::get_index(object, index).at(self.span())?;
+                    self.stack.push(value);
+                }
+                Op::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = ops::set_index(object, index, value).at(self.span())?;
+                    self.stack.push(value);
+                }
+                Op::Equal => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(Value::Boolean(left == right));
+                }
+                Op::NotEqual => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(Value::Boolean(left != right));
+                }
+                Op::Greater => self.binary(ops::greater)?,
+                Op::GreaterEqual => self.binary(ops::greater_equal)?,
+                Op::Less => self.binary(ops::less)?,
+                Op::LessEqual => self.binary(ops::less_equal)?,
+                Op::Add => self.binary(ops::add)?,
+                Op::Subtract => self.binary(ops::subtract)?,
+                Op::Multiply => self.binary(ops::multiply)?,
+                Op::Divide => self.binary(ops::divide)?,
+                Op::Modulo => self.binary(ops::modulo)?,
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(!value.is_truthy()));
+                }
+                Op::Negate => {
+                    let value = self.pop();
+                    let value = ops::negate(value).at(self.span())?;
+                    self.stack.push(value);
+                }
+                Op::Print => println!("{}", self.pop()),
+                Op::Jump(offset) => self.frame_mut().ip += offset as usize,
+                Op::JumpIfFalse(offset) => {
+                    if !self.peek().is_truthy() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                Op::Loop(offset) => {
+                    self.frame_mut().ip -= offset as usize;
+                    self.safe_point();
+                }
+                Op::Call(count) => {
+                    self.safe_point();
+                    // The script being run has a frame, but isn't a call. Tail calls
+                    // replace a frame, so don't need checking.
+                    if self.frames.len() > self.max_depth {
+                        return Err(stack_overflow_error(self.span(), self.max_depth));
+                    }
+                    self.call(count as usize)?
+                }
+                Op::TailCall(count) => {
+                    self.safe_point();
+                    let frames = self.frames.len();
+                    self.call(count as usize)?;
+                    if self.frames.len() > frames {
+                        self.replace_caller();
+                    } else if let Some(result) = self.return_from_frame(base_frame) {
+                        // Natives and classes without initializers return straight away
+                        return Ok(result);
+                    }
+                }
+                Op::Closure(index) => {
+                    let enclosing = self.frame().closure.clone();
+                    let function = match &enclosing.function.chunk.constants[index as usize] {
+                        Constant::Function(function) => function.clone(),
+                        _ => unreachable!("Closures are only made of functions"),
+                    };
+                    let base = self.frame().base;
+                    let upvalues = function
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                self.capture_upvalue(base + upvalue.index as usize)
+                            } else {
+                                enclosing.upvalues[upvalue.index as usize].clone()
+                            }
+                        })
+                        .collect();
+                    self.stack.push(Value::Closure(gc::closure(Closure {
+                        function,
+                        upvalues,
+                        globals: enclosing.globals.clone(),
+                    })));
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                Op::Unwind(slot) => {
+                    let len = self.frame().base + slot as usize;
+                    self.close_upvalues(len);
+                    self.stack.truncate(len);
+                }
+                Op::Return => {
+                    if let Some(result) = self.return_from_frame(base_frame) {
+                        return Ok(result);
+                    }
+                }
+                Op::Class { name, methods } => {
+                    let name = self.frame().closure.function.chunk.name(name).to_owned();
+                    let start = self.stack.len() - methods as usize;
+                    let methods = self
+                        .stack
+                        .drain(start..)
+                        .map(|method| match method {
+                            Value::Closure(closure) => {
+                                (closure.function.name().to_owned(), Method::Closure(closure))
+                            }
+                            _ => unreachable!("Methods are always closures"),
+                        })
+                        .collect();
+                    self.stack
+                        .push(Value::Class(gc::class(Class { name, methods })));
+                }
+                Op::List(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count as usize);
+                    self.stack.push(Value::List(gc::list(elements)));
+                }
+                Op::Interpolate(count) => {
+                    let parts = self.stack.split_off(self.stack.len() - count as usize);
+                    let string: String = parts.iter().map(Value::to_string).collect();
+                    self.stack.push(Value::String(string.into()));
+                }
+                Op::Map(count) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count as usize);
+                    let mut map = HashMap::new();
+                    let mut entries = entries.into_iter();
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        map.insert(MapKey::try_from(key).at(self.span())?, value);
+                    }
+                    self.stack.push(Value::Map(gc::map(map)));
+                }
+                Op::Import(path) => {
+                    let path = self.frame().closure.function.chunk.name(path).to_owned();
+                    let module = self.import(&path)?;
+                    self.stack.push(Value::Module(module));
+                }
+                Op::ImportAll => {
+                    let Value::Module(module) = self.pop() else {
+                        unreachable!("Only modules are imported")
+                    };
+                    let exports = module.exports().at(self.span())?;
+                    let mut globals = self.frame().closure.globals.borrow_mut();
+                    for (name, value) in exports {
+                        globals.define(&name, Some(value));
+                    }
+                }
+                Op::Throw => {
+                    let value = self.pop();
+                    return Err(thrown_error(self.span(), value));
+                }
+                Op::PushCatch(offset) => self.push_handler(HandlerKind::Catch, offset),
+                Op::PushFinally(offset) => self.push_handler(HandlerKind::Finally, offset),
+                Op::PopHandler => {
+                    self.handlers.pop();
+                }
+                Op::EndFinally => {
+                    return Err(self
+                        .pending
+                        .pop()
+                        .expect("Finally blocks end with a pending error"));
+                }
+                Op::PopPending => {
+                    self.pending.pop();
+                }
+            }
+        }
+    }
+
+    /// Unwinds to the innermost handler within the frames being run, or out of
+    /// them entirely if there isn't one
+    fn raise(&mut self, mut error: Error, base_frame: usize) -> Result<()> {
+        // Record the stack as it was where the error happened
+        if error.diagnostic().backtrace.is_empty() {
+            error.diagnostic_mut().backtrace = self.call_stack().into_iter().rev().collect();
+        }
+
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frame >= base_frame => self.handlers.pop().unwrap(),
+            _ => {
+                let base = self.frames[base_frame].base;
+                self.frames.truncate(base_frame);
+                self.close_upvalues(base);
+                self.stack.truncate(base);
+                return Err(error);
+            }
+        };
+
+        self.frames.truncate(handler.frame + 1);
+        self.close_upvalues(handler.stack_len);
+        self.stack.truncate(handler.stack_len);
+        self.pending.truncate(handler.pending_len);
+        self.frame_mut().ip = handler.target;
+        match handler.kind {
+            HandlerKind::Catch => self.stack.push(caught_value(&error, &self.error_class)),
+            HandlerKind::Finally => self.pending.push(error),
+        }
+        Ok(())
+    }
+
+    fn push_handler(&mut self, kind: HandlerKind, offset: u16) {
+        self.handlers.push(Handler {
+            kind,
+            frame: self.frames.len() - 1,
+            stack_len: self.stack.len(),
+            pending_len: self.pending.len(),
+            target: self.frame().ip + offset as usize,
+        });
+    }
+
+    /// Calls the value below the top `count` values on the stack, which are its
+    /// arguments
+    fn call(&mut self, count: usize) -> Result<()> {
+        let slot = self.stack.len() - count - 1;
+        match self.stack[slot].clone() {
+            Value::Closure(closure) => self.call_closure(closure, count, None),
+            Value::BoundMethod(bound) => {
+                self.stack[slot] = bound.receiver.clone();
+                self.call_closure(bound.method.clone(), count, None)
+            }
+            Value::Class(class) => {
+                let instance = Instance::new(class.clone());
+                self.stack[slot] = Value::Instance(gc::instance(instance));
+                match class.find_method("init") {
+                    Some(Method::Closure(initializer)) => {
+                        self.call_closure(initializer.clone(), count, Some(class.clone()))
+                    }
+                    Some(Method::Function(_)) => {
+                        unreachable!("Only the tree-walker declares uncompiled methods")
+                    }
+                    None => self.check_arity(0, count),
+                }
+            }
+            Value::NativeFunction(native) => {
+                self.check_arity(native.arity, count)?;
+                let arguments = self.stack.split_off(slot + 1);
+                self.stack.pop();
+
+                self.native_call = Some(CallFrame {
+                    function: native.name.clone(),
+                    call_site: self.span(),
+                });
+                let result = native.call(self, arguments).at(self.span());
+                // Natives aren't frames, so errors from them need the backtrace
+                // recording before the call is forgotten
+                let result = result.map_err(|mut error| {
+                    error.diagnostic_mut().backtrace =
+                        self.call_stack().into_iter().rev().collect();
+                    error
+                });
+                self.native_call = None;
+
+                self.stack.push(result?);
+                Ok(())
+            }
+            _ => Err(runtime_error(
+                self.span(),
+                ErrorCode::NotCallable,
+                "Can only call functions and classes",
+            )),
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        count: usize,
+        class: Option<Rc<Class>>,
+    ) -> Result<()> {
+        self.check_arity(closure.function.arity, count)?;
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base: self.stack.len() - count - 1,
+            class,
+        });
+        Ok(())
+    }
+
+    /// Moves the frame which has just been called down into the place of the
+    /// one which called it
+    fn replace_caller(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        let caller = self.frames.pop().unwrap();
+        self.close_upvalues(caller.base);
+        self.stack.drain(caller.base..frame.base);
+        self.frames.push(Frame {
+            base: caller.base,
+            ..frame
+        });
+    }
+
+    /// Pops the current frame, passing the value on top of the stack to its
+    /// caller. Returns the value instead if it was the frame at `base_frame`.
+    fn return_from_frame(&mut self, base_frame: usize) -> Option<Value> {
+        let result = self.pop();
+        let frame = self.frames.pop().unwrap();
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+        if self.frames.len() == base_frame {
+            return Some(result);
+        }
+        self.stack.push(result);
+        None
+    }
+
+    fn check_arity(&self, arity: u8, count: usize) -> Result<()> {
+        if count != arity as usize {
+            return Err(runtime_error(
+                self.span(),
+                ErrorCode::ArityMismatch,
+                format!("Expected {} arguments but got {}", arity, count),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Executes the module at `path` if it hasn't been already
+    fn import(&mut self, path: &str) -> Result<Rc<Module>> {
+        let span = self.span();
+        let pending = match self.loader.start(span, path)? {
+            Import::Done(module) => return Ok(module),
+            Import::Pending(pending) => pending,
+        };
+
+        // Run the module with its own globals
+        let globals = gc::environment(native::new_globals());
+        let result = compiler::compile(&pending.statements, &pending.locals)
+            .map_err(|error| pending.error(error))
+            .and_then(|script| {
+                self.run_script(gc::closure(Closure {
+                    function: script,
+                    upvalues: vec![],
+                    globals: globals.clone(),
+                }))
+            });
+        self.loader.finish(pending, globals, result)
+    }
+
+    /// Finds or creates the upvalue for a local, so that closures capturing
+    /// the same variable share it
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let index = self
+            .open_upvalues
+            .partition_point(|upvalue| open_slot(upvalue) < slot);
+        if let Some(upvalue) = self.open_upvalues.get(index) {
+            if open_slot(upvalue) == slot {
+                return upvalue.clone();
+            }
+        }
+        let upvalue = gc::upvalue(Upvalue::Open(slot));
+        self.open_upvalues.insert(index, upvalue.clone());
+        upvalue
+    }
+
+    /// Moves the locals from `slot` up which closures have captured off the
+    /// stack and into their upvalues
+    fn close_upvalues(&mut self, slot: usize) {
+        let index = self
+            .open_upvalues
+            .partition_point(|upvalue| open_slot(upvalue) < slot);
+        for upvalue in self.open_upvalues.drain(index..) {
+            // A variable can be captured by its own initializer before its slot
+            // exists
+            let value = self
+                .stack
+                .get(open_slot(&upvalue))
+                .cloned()
+                .unwrap_or(Value::Nil);
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+        }
+    }
+
+    /// Collects garbage if it's due. Only called between instructions which
+    /// loop or call, when every value in use is on the stack, so that every
+    /// long running program passes through one often.
+    fn safe_point(&mut self) {
+        if gc::collection_due() {
+            gc::collect(self.roots());
+        }
+    }
+
+    /// Everything the collector must keep, which is whatever the VM can still
+    /// reach
+    fn roots(&self) -> Vec<gc::Address> {
+        let mut roots = vec![gc::address(&self.globals)];
+        for frame in &self.frames {
+            roots.push(gc::address(&frame.closure));
+            roots.extend(frame.class.as_ref().map(gc::address));
+        }
+        roots.extend(self.open_upvalues.iter().map(gc::address));
+        roots.extend(
+            self.loader
+                .modules()
+                .map(|module| gc::address(module.globals())),
+        );
+        for value in &self.stack {
+            gc::trace_value(value, &mut roots);
+        }
+        for error in &self.pending {
+            if let Error::Thrown { value, .. } = error {
+                gc::trace_value(value, &mut roots);
+            }
+        }
+        roots
+    }
+
+    fn binary(&mut self, op: fn(Value, Value) -> Result<Value, Fault>) -> Result<()> {
+        let right = self.pop();
+        let left = self.pop();
+        let value = op(left, right).at(self.span())?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    /// Source of the instruction being executed
+    fn span(&self) -> Span {
+        let frame = self.frame();
+        frame.closure.function.chunk.spans[frame.ip - 1]
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Stack underflow")
+    }
+
+    fn peek(&self) -> &Value {
+        self.stack.last().expect("Stack underflow")
+    }
+}
+
+impl NativeContext for Vm {
+    fn call_stack(&self) -> Vec<CallFrame> {
+        let mut calls = vec![];
+        for (i, frame) in self.frames.iter().enumerate() {
+            // Running a script isn't a call
+            if frame.closure.function.kind == FunctionKind::Script {
+                continue;
+            }
+            let function = match &frame.class {
+                Some(class) => class.name.clone(),
+                None => frame.closure.function.name().to_owned(),
+            };
+            let caller = &self.frames[i - 1];
+            calls.push(CallFrame {
+                function,
+                call_site: caller.closure.function.chunk.spans[caller.ip - 1],
+            });
+        }
+        calls.extend(self.native_call.clone());
+        calls
+    }
+
+    fn collect_garbage(&mut self) -> gc::Stats {
+        gc::collect(self.roots())
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn open_slot(upvalue: &RefCell<Upvalue>) -> usize {
+    match *upvalue.borrow() {
+        Upvalue::Open(slot) => slot,
+        Upvalue::Closed(_) => unreachable!("Closed upvalues aren't tracked"),
+    }
+}
@@ -0,0 +1,6 @@
+//! A backend which compiles the resolved AST to bytecode and runs it on a stack
+//! machine, which is much faster than walking the tree
+pub mod chunk;
+pub mod compiler;
+pub mod machine;
+pub mod object;
@@ -0,0 +1,72 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use super::chunk::Chunk;
+use crate::runtime::{environment::Environment, value::Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    /// The top level of a file
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// Where a closure finds one of the variables it captures when it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueRef {
+    /// Whether the variable is a local of the enclosing function, rather than
+    /// one of its upvalues
+    pub is_local: bool,
+    pub index: u16,
+}
+
+/// A function compiled to bytecode, which becomes callable once it has been
+/// wrapped in a closure
+#[derive(Debug)]
+pub struct CompiledFunction {
+    pub name: Option<String>,
+    pub kind: FunctionKind,
+    pub arity: u8,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueRef>,
+}
+
+pub struct Closure {
+    pub function: Rc<CompiledFunction>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// Globals of the module the closure was created in
+    pub globals: Rc<RefCell<Environment>>,
+}
+
+/// A variable captured by a closure
+pub enum Upvalue {
+    /// The variable is still on the stack, at this slot
+    Open(usize),
+    /// The variable has gone out of scope, so the upvalue owns it
+    Closed(Value),
+}
+
+/// A method along with the instance it was accessed on
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+impl CompiledFunction {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("anonymous")
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format!("<fun {}>", self.function.name()))
+    }
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.method, f)
+    }
+}
@@ -1,19 +1,31 @@
 use std::{path::PathBuf, process::Command};
 
 use assert_cmd::prelude::{CommandCargoExt, OutputAssertExt};
+use predicates::{prelude::PredicateBooleanExt, str::contains};
+
+/// Extra arguments selecting each backend, which every test is run against
+const BACKENDS: [&[&str]; 2] = [&[], &["--vm"]];
+
+fn jlox(backend: &[&str]) -> Result<Command, Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("jlox")?;
+    cmd.args(backend);
+    Ok(cmd)
+}
 
 #[test]
 fn test1() -> Result<(), Box<dyn std::error::Error>> {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push("tests/test1.lox");
-    let mut cmd = Command::cargo_bin("jlox")?;
-    cmd.arg(path);
-    cmd.assert().success().stdout(
-        r#"one
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"one
 true
 3
 "#,
-    );
+        );
+    }
 
     Ok(())
 }
@@ -22,10 +34,11 @@ true
 fn test2() -> Result<(), Box<dyn std::error::Error>> {
     let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     path.push("tests/test2.lox");
-    let mut cmd = Command::cargo_bin("jlox")?;
-    cmd.arg(path);
-    cmd.assert().success().stdout(
-        r#"inner a
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"inner a
 outer b
 global c
 outer a
@@ -34,8 +47,618 @@ global c
 global a
 global b
 global c
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn class() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/class.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"3
+9
+2
+0
+Point
+Point instance
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn operators() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/operators.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"5
+9
+true
+true
+true
+fallback
+false
+true
+true
+6
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn list() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/list.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"[1, 2, 3]
+4
+[1, two, 3]
+4
+4
+[4]
+[1, two, 3]
+0
+[[0, 0], [5, 0]]
+[1, [...]]
+[[1, [...]], [[0, 0], [5, 0]], [[0, 0], [5, 0]]]
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn map() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/map.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"30
+{"alice": 30, "bob": 26, "carol": 41}
+3
+true
+26
+false
+nil
+71
+{nil: nil, true: true, 1: one, "1": string one}
+one
+zero
+[nil, true, 0, 1, 1]
+{"inner": {"outer": {...}}, "self": {...}}
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn loop_control() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/loop_control.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"0
+2
+3
+3
+0
+1
+2
+1
+escaped
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn lambda() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/lambda.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"[1, 4, 9]
+13
+<fun anonymous>
+6
+immediately invoked
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn closure() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/closure.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout("1\n2\n");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn gc() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/gc.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert()
+            .success()
+            .stdout("true\ntrue\ntrue\n[[1], done, {\"a\": [2]}]\n");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn dump_ast() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/dump_ast.lox");
+    let mut cmd = Command::cargo_bin("jlox")?;
+    cmd.arg("--dump-ast").arg(&path);
+    cmd.assert().success().stdout(
+        r#"(class Counter (fun init () (; (.= this n 0))) (fun bump () (; (.= this n (+ (. this n) 1))) (return this)))
+(block (var i 0) (while (< i 2) (print (* (- i) (group (+ 1 2)))) (= i (+ i 1))))
+(var twice (fun (f x) (return (call f (call f x)))))
+(try (block (throw (list 1 "two"))) (catch e (print (and (!= (index e 0) nil) (! false)))))
 "#,
     );
 
     Ok(())
 }
+
+#[test]
+fn modules() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/modules/main.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        // Imports are relative to the importing file, not the working directory
+        cmd.arg(&path).current_dir(env!("CARGO_MANIFEST_DIR"));
+        cmd.assert().success().stdout(
+            r#"loading math
+16
+<module math>
+9
+true
+2
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn import_cycle() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/modules/cycle_a.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert()
+            .code(70)
+            .stderr(contains("Import cycle: ").and(contains("cycle_b.lox -> ")));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn exceptions() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/exceptions.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"caught boom
+2
+divide by zero
+finally
+Only instances have fields
+24
+Operands must be two numbers or two strings.
+E401
+cleanup
+from try
+finally wins
+0
+after
+after
+2
+after
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn uncaught_exception() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/uncaught_exception.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().code(70).stdout("before\n").stderr(
+            contains("error[E414]: Uncaught exception: [1, 2]")
+                .and(contains("uncaught_exception.lox:2:5"))
+                .and(contains("2 |     throw [1, 2];\n  |     ^^^^^\n"))
+                .and(contains(
+                    "= help: thrown values can be caught with try/catch",
+                )),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn module_error_snippet() -> Result<(), Box<dyn std::error::Error>> {
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/modules"));
+        cmd.arg("faulty_main.lox");
+        cmd.assert().stdout("-1\n").stderr(
+            r#"error[E401]: Operand must be a number.
+ --> lib/faulty.lox:2:12
+  |
+2 |     return -x;
+  |            ^
+backtrace, most recent call first:
+    negate called at faulty_main.lox:4:13
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn static_error_exit_code() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/static_error.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        // Resolver errors stop anything from running
+        cmd.assert()
+            .code(65)
+            .stdout("")
+            .stderr(contains("error[E303]: Can't return from top-level code"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn static_errors_in_removed_code() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/dead_code.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        // Code the optimizer removes is still resolved first
+        cmd.assert().code(65).stdout("").stderr(
+            contains("error[E303]: Can't return from top-level code")
+                .and(contains("dead_code.lox:2:14"))
+                .and(contains("error[E305]: Can't use 'this' outside of a class"))
+                .and(contains("dead_code.lox:3:17")),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn folding() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/folding.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        // Folded expressions print the same as the ones which can't be folded,
+        // and operators which would fail still fail where they are
+        cmd.assert()
+            .code(70)
+            .stdout(
+                r#"5
+5
+3.0
+3.0
+ab2
+ab2
+true
+true
+3.0
+3.0
+a
+a
+live
+live
+"#,
+            )
+            .stderr(
+                contains("error[E401]: Operand must be a number.")
+                    .and(contains("folding.lox:24:11")),
+            );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn runtime_error_exit_code() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/runtime_error.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().code(70).stdout("ran\n").stderr(contains(
+            "error[E401]: Operands must be two numbers or two strings.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn missing_file_exit_code() -> Result<(), Box<dyn std::error::Error>> {
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg("does_not_exist.lox");
+        cmd.assert()
+            .code(74)
+            .stderr(contains("can't read 'does_not_exist.lox'"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn backtrace() -> Result<(), Box<dyn std::error::Error>> {
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"));
+        cmd.arg("backtrace.lox");
+        cmd.assert()
+            .code(70)
+            .stdout("[inner, outer]\n")
+            .stderr(contains(
+                r#"backtrace, most recent call first:
+    inner called at backtrace.lox:7:10
+    outer called at backtrace.lox:10:6
+"#,
+            ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stack_overflow() -> Result<(), Box<dyn std::error::Error>> {
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"));
+        cmd.args(["--max-depth", "100", "stack_overflow.lox"]);
+        cmd.assert()
+            .code(70)
+            .stdout("Stack overflow\nE415\n100\n")
+            .stderr(contains(
+                r#"backtrace, most recent call first:
+    sum called at stack_overflow.lox:16:17
+    ... repeated 98 more times
+    sum called at stack_overflow.lox:18:4
+"#,
+            ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn deep_recursion() -> Result<(), Box<dyn std::error::Error>> {
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"));
+        cmd.arg("stack_overflow.lox");
+        cmd.assert()
+            .code(70)
+            .stdout("Stack overflow\nE415\n10000\n")
+            .stderr(contains("calls can only be nested 10000 deep"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn native_stack_overflow() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/native_stack.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout("E415\n");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn max_depth_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/test1.lox");
+    // The tree-walker can't be given a stack big enough for this, but the VM
+    // doesn't need one
+    let mut cmd = jlox(&[])?;
+    cmd.args(["--max-depth", "100000000"]).arg(&path);
+    cmd.assert()
+        .code(64)
+        .stdout("")
+        .stderr(contains("--max-depth can be at most"));
+
+    let mut cmd = jlox(&["--vm"])?;
+    cmd.args(["--max-depth", "100000000"]).arg(&path);
+    cmd.assert().success();
+
+    Ok(())
+}
+
+#[test]
+fn tail_call() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/tail_call.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"1000000
+false
+walked
+3
+caught failed
+3
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn strings() -> Result<(), Box<dyn std::error::Error>> {
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"));
+        cmd.arg("strings.lox");
+        cmd.assert()
+            .code(70)
+            .stdout(
+                "tab:\t|
+quote: \" backslash: \\
+line one
+line two
+Hé😀
+naïve naïve
+5
+roses
+  are red
+",
+            )
+            .stderr(contains(
+                r#" --> strings.lox:14:11
+   |
+14 | print "ü" + 1;
+   |           ^
+"#,
+            ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn interpolation() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/interpolation.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"hello world!
+3 + 6 = 9
+nested: inner world
+map: 1
+values: [1, nil, true] nil 1.5
+(1, 2) is a Point
+hi there
+escaped: ${name}
+"#,
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn numbers() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/numbers.lox");
+    for backend in BACKENDS {
+        let mut cmd = jlox(backend)?;
+        cmd.arg(&path);
+        cmd.assert().success().stdout(
+            r#"255
+11
+1000000
+1e-9
+25000000000.0
+true
+3735928559
+3.1415
+3
+-3
+1
+-1
+3.5
+1.5
+1.5
+true
+true
+true
+true
+true
+false
+3
+3.0
+9007199254740994
+-9223372036854775808
+{1: float}
+E416: Integer overflow
+0
+E417: Division by zero
+inf
+"#,
+        );
+    }
+
+    Ok(())
+}